@@ -2,15 +2,383 @@
 //!
 //! This script only executes when using `cargo` to build the project.
 //!
+//! It generates `memory.x` from one of the memory layouts below, followed by
+//! the layout-independent sections in `s32z2.x`. Pick a layout with the
+//! `S32Z2_LAYOUT` environment variable. The default, and for now the only
+//! one, is `rtu0` - the MC_ME partition, the GIC address, the MPU's
+//! peripheral regions and the interrupt numbers are all RTU0's, so only the
+//! RAM could move to another cluster.
+//!
+//! The MPU regions for code, data and shared data come from the same layout,
+//! via a generated `memory_map.rs`. The linker script checks every section
+//! lands inside one of them, so the build fails if they ever diverge.
+//!
+//! Regions marked for scrubbing go into the ECC table, which the start-up code
+//! zeroes before anything else touches RAM. Override the list with a comma
+//! separated set of region names in `S32Z2_ECC_SCRUB`. Only the data and shared
+//! RAMs can be listed. The code region already holds the program, and the
+//! TCMs aren't enabled yet - each core initialises its own TCMs once it has
+//! enabled them.
+//!
 //! Copyright (c) Ferrous Systems, 2025
 
 use std::io::Write;
 
+/// A region of memory in the `MEMORY` block
+struct Region {
+    /// The name used in the linker script
+    name: &'static str,
+    /// Linker script attributes
+    attrs: &'static str,
+    /// Start address
+    origin: u32,
+    /// Length in bytes
+    length: u32,
+    /// Does the start-up code erase this region, to initialise the ECC?
+    scrub: bool,
+}
+
+/// A memory layout for one of the Cortex-R52 clusters
+struct Layout {
+    /// The name used to select this layout
+    name: &'static str,
+    /// Every region we might use
+    regions: &'static [Region],
+    /// Where the code, read-only data and vector table goes
+    code: &'static str,
+    /// Each core's data RAM, which holds its `.data`, `.bss` and stacks
+    core_data: [&'static str; 4],
+    /// Where `.shared` goes
+    shared: &'static str,
+    /// The TCMs, A to C
+    tcm: [&'static str; 3],
+}
+
+/// The layout for RTU0, running from Code RAM
+const RTU0: Layout = Layout {
+    name: "rtu0",
+    regions: &[
+        Region {
+            name: "R52_0_0_TCMA",
+            attrs: "rw",
+            origin: 0x3000_0000,
+            length: 0x1_0000,
+            scrub: false,
+        },
+        Region {
+            name: "R52_0_0_TCMB",
+            attrs: "rw",
+            origin: 0x3010_0000,
+            length: 0x4000,
+            scrub: false,
+        },
+        Region {
+            name: "R52_0_0_TCMC",
+            attrs: "rw",
+            origin: 0x3020_0000,
+            length: 0x4000,
+            scrub: false,
+        },
+        Region {
+            name: "R52_0_0_CODE_RAM",
+            attrs: "rx",
+            origin: 0x3210_0000,
+            length: 0x1C_0000,
+            scrub: false,
+        },
+        Region {
+            name: "R52_0_0_DATA_RAM",
+            attrs: "rw",
+            origin: 0x3178_0000,
+            length: 0x4_0000,
+            scrub: true,
+        },
+        Region {
+            name: "R52_0_1_DATA_RAM",
+            attrs: "rw",
+            origin: 0x317C_0000,
+            length: 0x4_0000,
+            scrub: true,
+        },
+        Region {
+            name: "R52_0_2_DATA_RAM",
+            attrs: "rw",
+            origin: 0x3180_0000,
+            length: 0x4_0000,
+            scrub: true,
+        },
+        // The Data RAM ends at 0x3187_FFFF (see the SRAMCTL_D2 set-up in
+        // `t32-scripts/s32z27_init_rtu0_sram.cmm`), so the Shared RAM is the
+        // top 64 KiB of it, taken from core 3.
+        Region {
+            name: "R52_0_3_DATA_RAM",
+            attrs: "rw",
+            origin: 0x3184_0000,
+            length: 0x3_0000,
+            scrub: true,
+        },
+        Region {
+            name: "RTU0_SHARED_RAM",
+            attrs: "rw",
+            origin: 0x3187_0000,
+            length: 0x1_0000,
+            scrub: true,
+        },
+    ],
+    code: "R52_0_0_CODE_RAM",
+    core_data: [
+        "R52_0_0_DATA_RAM",
+        "R52_0_1_DATA_RAM",
+        "R52_0_2_DATA_RAM",
+        "R52_0_3_DATA_RAM",
+    ],
+    shared: "RTU0_SHARED_RAM",
+    tcm: ["R52_0_0_TCMA", "R52_0_0_TCMB", "R52_0_0_TCMC"],
+};
+
+/// Every layout we know about
+const LAYOUTS: &[&Layout] = &[&RTU0];
+
+/// ECC table flag: scrub this entry with 32-byte `stm` instructions
+const ECC_FLAG_FAST: u32 = 1 << 0;
+
+/// Stack size for the EL2 monitor, with the `hypervisor` feature
+const MONITOR_STACK_SIZE: u32 = 0x2000;
+
 fn main() {
     arm_targets::process();
-    write("memory.x", include_bytes!("s32z2.x"));
-    // Use the cortex-r-rt linker script
-    println!("cargo:rustc-link-arg=-Tlink.x");
+    let layout = select_layout();
+    let mut memory_x = generate(layout);
+    memory_x.push_str(include_str!("s32z2.x"));
+    write("memory.x", memory_x.as_bytes());
+    write("memory_map.rs", memory_map(layout).as_bytes());
+    // Use the cortex-r-rt linker script, unless this is a host build for the
+    // unit tests
+    if std::env::var("CARGO_CFG_TARGET_ARCH").as_deref() == Ok("arm") {
+        println!("cargo:rustc-link-arg=-Tlink.x");
+    }
+    println!("cargo:rerun-if-changed=s32z2.x");
+    println!("cargo:rerun-if-env-changed=S32Z2_LAYOUT");
+    println!("cargo:rerun-if-env-changed=S32Z2_ECC_SCRUB");
+}
+
+/// Pick a layout using the environment variable
+fn select_layout() -> &'static Layout {
+    let name = std::env::var("S32Z2_LAYOUT").unwrap_or_else(|_| "rtu0".to_string());
+    LAYOUTS
+        .iter()
+        .find(|layout| layout.name == name)
+        .unwrap_or_else(|| {
+            let names: Vec<&str> = LAYOUTS.iter().map(|layout| layout.name).collect();
+            panic!("Unknown S32Z2_LAYOUT {name:?} - pick one of {names:?}")
+        })
+}
+
+/// Generate the layout-specific part of `memory.x`
+fn generate(layout: &Layout) -> String {
+    let mut out = format!(
+        "/* Generated by build.rs for the `{}` memory layout - do not edit */\n\n",
+        layout.name
+    );
+
+    out.push_str("MEMORY {\n");
+    for region in layout.regions {
+        let prefix = format!("    {} ({})", region.name, region.attrs);
+        out.push_str(&format!(
+            "{prefix:<28}: ORIGIN = {:#010X}, LENGTH = {:#X}\n",
+            region.origin, region.length
+        ));
+    }
+    out.push_str("}\n\n");
+
+    out.push_str(&format!("REGION_ALIAS(\"VECTORS\", {});\n", layout.code));
+    out.push_str(&format!("REGION_ALIAS(\"CODE\", {});\n", layout.code));
+    out.push_str(&format!(
+        "REGION_ALIAS(\"DATA\", {});\n",
+        layout.core_data[0]
+    ));
+    for (core, region) in layout.core_data.iter().enumerate().skip(1) {
+        out.push_str(&format!("REGION_ALIAS(\"CORE{core}_DATA\", {region});\n"));
+    }
+    out.push_str(&format!("REGION_ALIAS(\"SHARED\", {});\n\n", layout.shared));
+
+    for (core, region) in layout.core_data.iter().enumerate().skip(1) {
+        out.push_str(&format!(
+            "__core{core}_stack_top = ORIGIN({region}) + LENGTH({region});\n"
+        ));
+    }
+    out.push('\n');
+
+    for (letter, region) in ["A", "B", "C"].iter().zip(layout.tcm) {
+        out.push_str(&format!("__TCM{letter}_Start  = ORIGIN({region});\n"));
+        out.push_str(&format!("__TCM{letter}_Length = LENGTH({region});\n"));
+    }
+    out.push('\n');
+
+    let map = MpuMap::new(layout);
+    for (name, (start, end)) in [
+        ("code", map.code),
+        ("data", map.data),
+        ("shared", map.shared),
+    ] {
+        out.push_str(&format!("__mpu_{name}_start = {start:#010X};\n"));
+        out.push_str(&format!("__mpu_{name}_end   = {end:#010X};\n"));
+    }
+    out.push('\n');
+
+    // The EL2 monitor's stack lives in its own RAM, which guests can't reach
+    let monitor_stack = if std::env::var_os("CARGO_FEATURE_HYPERVISOR").is_some() {
+        MONITOR_STACK_SIZE
+    } else {
+        0
+    };
+    out.push_str(&format!(
+        "PROVIDE(_monitor_stack_size = {monitor_stack:#X});\n"
+    ));
+    // `_default_start` leaves a HYP stack at the top of core 0's Data RAM,
+    // but the monitor starts its guest below that, with no HYP stack
+    let core0_hyp_stack = if monitor_stack == 0 {
+        "_hyp_stack_size"
+    } else {
+        "0"
+    };
+    out.push_str(&format!("__core0_hyp_stack_size = {core0_hyp_stack};\n\n"));
+
+    out.push_str(&ecc_table(layout));
+    out
+}
+
+/// The RAM ranges the MPU must cover, as (start, end) with an exclusive end
+struct MpuMap {
+    /// Code, read-only data and the vector table
+    code: (u32, u32),
+    /// Every core's data RAM, which must be contiguous
+    data: (u32, u32),
+    /// Data shared between the cores
+    shared: (u32, u32),
+}
+
+impl MpuMap {
+    /// Work out the MPU ranges for a layout
+    fn new(layout: &Layout) -> MpuMap {
+        let code = region(layout, layout.code);
+        let shared = region(layout, layout.shared);
+        let mut data = (u32::MAX, 0);
+        for name in layout.core_data {
+            let r = region(layout, name);
+            if data.1 != 0 && r.origin != data.1 {
+                panic!(
+                    "Layout {}: the data RAMs must be contiguous for the MPU",
+                    layout.name
+                );
+            }
+            data.0 = data.0.min(r.origin);
+            data.1 = r.origin + r.length;
+        }
+        MpuMap {
+            code: (code.origin, code.origin + code.length),
+            data,
+            shared: (shared.origin, shared.origin + shared.length),
+        }
+    }
+}
+
+/// Find a region in a layout by name
+fn region<'a>(layout: &'a Layout, name: &str) -> &'a Region {
+    layout
+        .regions
+        .iter()
+        .find(|region| region.name == name)
+        .unwrap_or_else(|| panic!("Layout {}: no region called {name:?}", layout.name))
+}
+
+/// Generate the Rust constants the MPU code uses
+fn memory_map(layout: &Layout) -> String {
+    let map = MpuMap::new(layout);
+    let mut out = format!(
+        "// Generated by build.rs for the `{}` memory layout - do not edit\n\n",
+        layout.name
+    );
+    for (name, what, (start, end)) in [
+        ("CODE", "code, read-only data and vectors", map.code),
+        ("DATA", "each core's data RAM", map.data),
+        ("SHARED", "data shared between cores", map.shared),
+    ] {
+        out.push_str(&format!(
+            "/// The RAM holding {what}\npub(crate) const {name}_RANGE: core::ops::RangeInclusive<usize> = {start:#010X}..={:#010X};\n\n",
+            end - 1
+        ));
+    }
+    out.push_str("/// Each core's data RAM\npub(crate) const CORE_DATA_RANGES: [core::ops::RangeInclusive<usize>; 4] = [\n");
+    for name in layout.core_data {
+        let r = region(layout, name);
+        out.push_str(&format!(
+            "    {:#010X}..={:#010X},\n",
+            r.origin,
+            r.origin + r.length - 1
+        ));
+    }
+    out.push_str("];\n");
+    out
+}
+
+/// Find a region the start-up code can scrub, by name
+///
+/// That's any data or shared RAM. Scrubbing the code region would erase the
+/// program we're running, and the TCMs aren't enabled until later.
+fn scrubbable_region<'a>(layout: &'a Layout, name: &str) -> &'a Region {
+    if name == layout.code || layout.tcm.contains(&name) {
+        panic!(
+            "S32Z2_ECC_SCRUB: {name:?} can't be scrubbed - pick from {:?}",
+            layout
+                .regions
+                .iter()
+                .map(|region| region.name)
+                .filter(|name| *name != layout.code && !layout.tcm.contains(name))
+                .collect::<Vec<_>>()
+        );
+    }
+    region(layout, name)
+}
+
+/// Generate the ECC table, and the link-time checks on it
+fn ecc_table(layout: &Layout) -> String {
+    let scrub: Vec<&Region> = match std::env::var("S32Z2_ECC_SCRUB") {
+        Ok(list) => list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| scrubbable_region(layout, name))
+            .collect(),
+        Err(_) => layout.regions.iter().filter(|r| r.scrub).collect(),
+    };
+    let fast = std::env::var_os("CARGO_FEATURE_FAST_ECC_SCRUB").is_some();
+
+    let mut out = String::from(
+        "SECTIONS {\n    /*\n     * Regions the start-up code erases to initialise their ECC. Each entry\n     * is (start, length, flags). Entries are written with 64-bit stores,\n     * or 32-byte `stm` instructions if the fast flag is set, so must be\n     * aligned to match.\n     */\n    .ecc.table :\n    {\n        . = ALIGN(4);\n        __ecc_table_start__ = .;\n",
+    );
+    let mut asserts = String::new();
+    for region in &scrub {
+        let name = region.name;
+        let (flags, align) = if fast && region.origin % 32 == 0 && region.length % 32 == 0 {
+            (ECC_FLAG_FAST, 32)
+        } else {
+            (0, 8)
+        };
+        out.push_str(&format!(
+            "        LONG (ORIGIN({name}))\n        LONG (LENGTH({name}))\n        LONG ({flags:#x})\n"
+        ));
+        asserts.push_str(&format!(
+            "ASSERT(ORIGIN({name}) % {align} == 0, \"ECC scrub of {name} must start {align}-byte aligned\");\n"
+        ));
+        asserts.push_str(&format!(
+            "ASSERT(LENGTH({name}) % {align} == 0, \"ECC scrub of {name} must be a multiple of {align} bytes\");\n"
+        ));
+    }
+    out.push_str("        __ecc_table_end__ = .;\n    } > CODE\n} INSERT AFTER .text;\n\n");
+    out.push_str(&asserts);
+    out.push('\n');
+    out
 }
 
 fn write(file: &str, contents: &[u8]) {
@@ -1,37 +1,236 @@
-MEMORY {
-    R52_0_0_TCMA (rw)       : ORIGIN = 0x30000000, LENGTH = 0x10000
-    R52_0_0_TCMB (rw)       : ORIGIN = 0x30100000, LENGTH = 0x4000
-    R52_0_0_TCMC (rw)       : ORIGIN = 0x30200000, LENGTH = 0x4000
-    R52_0_0_CODE_RAM (rx)   : ORIGIN = 0x32100000, LENGTH = 0x1C0000
-    R52_0_0_DATA_RAM (rw)   : ORIGIN = 0x31780000, LENGTH = 0x40000
-}
+/*
+ * Sections for the S32Z2 which don't depend on the memory layout.
+ *
+ * build.rs puts the MEMORY block, REGION_ALIASes, TCM symbols and ECC table
+ * for the chosen layout in front of this, to make memory.x.
+ */
 
 SECTIONS {
     /*
-     * ECC initialization is done by 64-bit writes thus the pointers and lengths
-     * found within this table must be 8-byte aligned. The table itself only
-     * needs to be 4-byte aligned.
+     * The start-up code for cores 1 to 3 uses this table to find their stack
+     * top and their own .bss and .data. Each entry is six words.
      */
-    .ecc.table :
+    .core.table :
     {
         . = ALIGN(4);
-        __ecc_table_start__ = .;
+        __core_table_start__ = .;
 
-        /* Erase all of R52_0_0_DATA_RAM - these values are a multiple of 8 */
-        LONG (ORIGIN(R52_0_0_DATA_RAM))
-        LONG (LENGTH(R52_0_0_DATA_RAM))
+        /* Core 1 */
+        LONG (__core1_stack_top)
+        LONG (__core1_bss_start)
+        LONG (__core1_bss_end)
+        LONG (__core1_data_start)
+        LONG (__core1_data_end)
+        LONG (LOADADDR(.core1.data))
 
-        __ecc_table_end__ = .;
-    } > R52_0_0_CODE_RAM
+        /* Core 2 */
+        LONG (__core2_stack_top)
+        LONG (__core2_bss_start)
+        LONG (__core2_bss_end)
+        LONG (__core2_data_start)
+        LONG (__core2_data_end)
+        LONG (LOADADDR(.core2.data))
+
+        /* Core 3 */
+        LONG (__core3_stack_top)
+        LONG (__core3_bss_start)
+        LONG (__core3_bss_end)
+        LONG (__core3_data_start)
+        LONG (__core3_data_end)
+        LONG (LOADADDR(.core3.data))
+
+        __core_table_end__ = .;
+    } > CODE
+
+    /*
+     * Core 0 sets this flag to release the other cores. It lives in Code RAM
+     * because the other cores read it before the Data RAM ECC is initialised.
+     * It gets its own 64-byte MPU region, as the rest of Code RAM is
+     * read-only.
+     */
+    .core_release : ALIGN(64)
+    {
+        __core_release_start = .;
+        KEEP(*(.core_release));
+        . = ALIGN(64);
+        __core_release_end = .;
+    } > CODE
 } INSERT AFTER .text;
 
-REGION_ALIAS("VECTORS", R52_0_0_CODE_RAM);
-REGION_ALIAS("CODE", R52_0_0_CODE_RAM);
-REGION_ALIAS("DATA", R52_0_0_DATA_RAM);
+SECTIONS {
+    /*
+     * The EL2 monitor's statics and stack, with the `hypervisor` feature. The
+     * EL2 MPU hides this from the EL1 guests. The monitor zeroes it before
+     * use, so only put statics here whose initial value is all zeroes.
+     */
+    .hyp.bss (NOLOAD) : ALIGN(64)
+    {
+        __hyp_start = .;
+        *(.hyp.bss .hyp.bss.*);
+        . = ALIGN(8);
+        . += _monitor_stack_size;
+        __hyp_stack_top = .;
+        . = ALIGN(64);
+        __hyp_end = .;
+    } > DATA
+
+    /*
+     * Memory for the monitor's other guests, with the `hypervisor` feature.
+     * It sits right above the monitor's own RAM, and the primary guest's
+     * regions leave both out, so the primary guest can't reach it. It is not
+     * loaded, but the ECC initialisation zeroes it, so only put statics here
+     * whose initial value is all zeroes.
+     */
+    .guest.bss (NOLOAD) : ALIGN(64)
+    {
+        __guest_start = .;
+        *(.guest.bss .guest.bss.*);
+        . = ALIGN(64);
+        __guest_end = .;
+    } > DATA
+
+    /*
+     * Memory for EL0 tasks, on core 0. While a task runs, the MPU lets EL0
+     * use this, and everything above it up to the bottom of the stacks. It is
+     * not loaded, but the ECC initialisation zeroes it, so only put statics
+     * here whose initial value is all zeroes.
+     */
+    .task (NOLOAD) : ALIGN(64)
+    {
+        __task_start = .;
+        *(.task .task.*);
+        . = ALIGN(64);
+        __task_end = .;
+    } > DATA
+
+    /* Per-core .data and .bss, for cores 1 to 3 */
+    .core1.data : ALIGN(4)
+    {
+        __core1_data_start = .;
+        *(.core1.data .core1.data.*);
+        . = ALIGN(4);
+        __core1_data_end = .;
+    } > CORE1_DATA AT > CODE
+
+    .core1.bss (NOLOAD) : ALIGN(4)
+    {
+        __core1_bss_start = .;
+        *(.core1.bss .core1.bss.*);
+        . = ALIGN(4);
+        __core1_bss_end = .;
+    } > CORE1_DATA
+
+    .core2.data : ALIGN(4)
+    {
+        __core2_data_start = .;
+        *(.core2.data .core2.data.*);
+        . = ALIGN(4);
+        __core2_data_end = .;
+    } > CORE2_DATA AT > CODE
+
+    .core2.bss (NOLOAD) : ALIGN(4)
+    {
+        __core2_bss_start = .;
+        *(.core2.bss .core2.bss.*);
+        . = ALIGN(4);
+        __core2_bss_end = .;
+    } > CORE2_DATA
+
+    .core3.data : ALIGN(4)
+    {
+        __core3_data_start = .;
+        *(.core3.data .core3.data.*);
+        . = ALIGN(4);
+        __core3_data_end = .;
+    } > CORE3_DATA AT > CODE
+
+    .core3.bss (NOLOAD) : ALIGN(4)
+    {
+        __core3_bss_start = .;
+        *(.core3.bss .core3.bss.*);
+        . = ALIGN(4);
+        __core3_bss_end = .;
+    } > CORE3_DATA
+
+    /*
+     * Data shared between cores. The MPU makes this non-cacheable. It is not
+     * loaded, but the ECC initialisation zeroes it, so only put statics here
+     * whose initial value is all zeroes.
+     */
+    .shared (NOLOAD) : ALIGN(8)
+    {
+        __shared_start = .;
+        *(.shared .shared.*);
+        . = ALIGN(8);
+        __shared_end = .;
+    } > SHARED
+} INSERT AFTER .bss;
+
+/*
+ * The SYS mode stack sits below the other mode stacks. Give it a fixed size,
+ * so the MPU can put a guard region at the bottom of it.
+ */
+PROVIDE(_sys_stack_size = 0x8000);
+
+/* Cores without their own entry point just park themselves */
+PROVIDE(s32z2_main_core1 = _s32z2_default_core_main);
+PROVIDE(s32z2_main_core2 = _s32z2_default_core_main);
+PROVIDE(s32z2_main_core3 = _s32z2_default_core_main);
+
+/* FIQs go to the Group 0 dispatcher unless the application takes them */
+PROVIDE(_fiq_handler = _s32z2_default_fiq_handler);
+
+/*
+ * Core 0 drops straight to EL1, unless the `hypervisor` feature provides an
+ * EL2 monitor. The default monitor runs the application as its only guest.
+ */
+PROVIDE(_s32z2_el2_start = _default_start);
+PROVIDE(s32z2_hyp_main = _s32z2_default_hyp_main);
+
+/*
+ * Every section must sit inside the MPU regions set up by `mpu.rs`, which
+ * come from the same layout in build.rs.
+ */
+ASSERT(ADDR(.text) >= __mpu_code_start && ADDR(.text) + SIZEOF(.text) <= __mpu_code_end,
+    ".text is outside the MPU code region");
+ASSERT(ADDR(.rodata) >= __mpu_code_start && ADDR(.rodata) + SIZEOF(.rodata) <= __mpu_code_end,
+    ".rodata is outside the MPU code region");
+ASSERT(ADDR(.core.table) >= __mpu_code_start && ADDR(.core_release) + SIZEOF(.core_release) <= __mpu_code_end,
+    "The core table or release flag is outside the MPU code region");
+ASSERT(ADDR(.data) >= __mpu_data_start && ADDR(.data) + SIZEOF(.data) <= __mpu_data_end,
+    ".data is outside the MPU data region");
+ASSERT(ADDR(.bss) >= __mpu_data_start && ADDR(.bss) + SIZEOF(.bss) <= __mpu_data_end,
+    ".bss is outside the MPU data region");
+ASSERT(__hyp_start >= __mpu_data_start && __hyp_end <= __mpu_data_end,
+    ".hyp.bss is outside the MPU data region");
+ASSERT(__guest_start == __hyp_end && __guest_end <= __mpu_data_end,
+    ".guest.bss must sit right above .hyp.bss, inside the MPU data region");
+ASSERT(__task_start >= __mpu_data_start && __task_end <= __mpu_data_end,
+    ".task is outside the MPU data region");
+ASSERT(__core1_data_start >= __mpu_data_start && __core3_bss_end <= __mpu_data_end,
+    "Per-core .data or .bss is outside the MPU data region");
+ASSERT(_stack_top <= __mpu_data_end && __core3_stack_top <= __mpu_data_end,
+    "A stack is outside the MPU data region");
+ASSERT(__shared_start >= __mpu_shared_start && __shared_end <= __mpu_shared_end,
+    ".shared is outside the MPU shared region");
 
-__TCMA_Start  = ORIGIN(R52_0_0_TCMA);
-__TCMA_Length = LENGTH(R52_0_0_TCMA);
-__TCMB_Start  = ORIGIN(R52_0_0_TCMB);
-__TCMB_Length = LENGTH(R52_0_0_TCMB);
-__TCMC_Start  = ORIGIN(R52_0_0_TCMC);
-__TCMC_Length = LENGTH(R52_0_0_TCMC);
+/*
+ * The MPU makes the lowest 64 bytes of each mode's stack a no-access guard
+ * region, so the stacks must be a multiple of 64 bytes, and must not run into
+ * the statics below them. On core 0 they start below the HYP stack, if
+ * build.rs says there is one.
+ */
+ASSERT(_und_stack_size % 64 == 0 && _svc_stack_size % 64 == 0 && _abt_stack_size % 64 == 0
+    && _irq_stack_size % 64 == 0 && _fiq_stack_size % 64 == 0 && _sys_stack_size % 64 == 0
+    && __core0_hyp_stack_size % 64 == 0,
+    "Stack sizes must be a multiple of 64 bytes, for the MPU guard regions");
+__stacks_size = _und_stack_size + _svc_stack_size + _abt_stack_size
+    + _irq_stack_size + _fiq_stack_size + _sys_stack_size;
+ASSERT(_stack_top - __core0_hyp_stack_size - __stacks_size >= __task_end,
+    "Core 0's stacks overlap its statics");
+ASSERT(__core1_stack_top - __stacks_size >= __core1_bss_end,
+    "Core 1's stacks overlap its statics");
+ASSERT(__core2_stack_top - __stacks_size >= __core2_bss_end,
+    "Core 2's stacks overlap its statics");
+ASSERT(__core3_stack_top - __stacks_size >= __core3_bss_end,
+    "Core 3's stacks overlap its statics");
@@ -0,0 +1,197 @@
+//! A fixed-size queue of alarms, as used by [`crate::time`]
+//!
+//! This is plain Rust with no hardware dependencies, so it can be tested on
+//! the host. Deadlines are raw tick counts, and callbacks are opaque non-zero
+//! `usize` values which the caller converts to and from function pointers.
+//!
+//! All-zero bytes is a valid empty queue - a free slot is one whose callback
+//! is zero - so a queue can live in a `NOLOAD` section that the start-up code
+//! zeroes. Each slot also has a generation count, which is bumped whenever the
+//! slot is reused, so an [`AlarmId`] for an alarm that has already fired
+//! cannot cancel a newer alarm in the same slot.
+
+use core::num::NonZeroUsize;
+
+/// Identifies an alarm, so you can cancel it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AlarmId {
+    index: usize,
+    generation: u32,
+}
+
+/// One slot in an [`AlarmQueue`]
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct Slot {
+    /// When the alarm expires, in ticks
+    deadline: u64,
+    /// The callback, or zero if this slot is free
+    callback: usize,
+    /// How many times this slot has been used
+    generation: u32,
+}
+
+/// A queue of up to `N` alarms
+#[derive(Debug)]
+#[repr(C)]
+pub struct AlarmQueue<const N: usize> {
+    slots: [Slot; N],
+}
+
+impl<const N: usize> AlarmQueue<N> {
+    /// Create a new, empty, queue
+    pub const fn new() -> AlarmQueue<N> {
+        AlarmQueue {
+            slots: [Slot {
+                deadline: 0,
+                callback: 0,
+                generation: 0,
+            }; N],
+        }
+    }
+
+    /// Add an alarm to the queue
+    ///
+    /// Returns `None` if every slot is in use.
+    pub fn insert(&mut self, deadline: u64, callback: NonZeroUsize) -> Option<AlarmId> {
+        let (index, slot) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, slot)| slot.callback == 0)?;
+        slot.deadline = deadline;
+        slot.callback = callback.get();
+        slot.generation = slot.generation.wrapping_add(1);
+        Some(AlarmId {
+            index,
+            generation: slot.generation,
+        })
+    }
+
+    /// Remove an alarm from the queue
+    ///
+    /// Returns `false` if the alarm has already expired or been cancelled.
+    pub fn cancel(&mut self, id: AlarmId) -> bool {
+        match self.slots.get_mut(id.index) {
+            Some(slot) if slot.callback != 0 && slot.generation == id.generation => {
+                slot.callback = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Remove the alarm with the earliest deadline at or before `now`, if any
+    ///
+    /// Gives back its id and callback.
+    pub fn pop_expired(&mut self, now: u64) -> Option<(AlarmId, NonZeroUsize)> {
+        let (index, slot) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, slot)| slot.callback != 0 && slot.deadline <= now)
+            .min_by_key(|(_, slot)| slot.deadline)?;
+        let callback = NonZeroUsize::new(core::mem::take(&mut slot.callback))?;
+        Some((
+            AlarmId {
+                index,
+                generation: slot.generation,
+            },
+            callback,
+        ))
+    }
+
+    /// The earliest deadline in the queue, if any
+    pub fn earliest(&self) -> Option<u64> {
+        self.slots
+            .iter()
+            .filter(|slot| slot.callback != 0)
+            .map(|slot| slot.deadline)
+            .min()
+    }
+}
+
+impl<const N: usize> Default for AlarmQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn callback(value: usize) -> NonZeroUsize {
+        NonZeroUsize::new(value).unwrap()
+    }
+
+    #[test]
+    fn starts_empty() {
+        let mut queue = AlarmQueue::<4>::new();
+        assert_eq!(queue.earliest(), None);
+        assert_eq!(queue.pop_expired(u64::MAX), None);
+    }
+
+    #[test]
+    fn all_zero_is_empty() {
+        // SAFETY: every field of the queue is an integer
+        let mut queue: AlarmQueue<4> = unsafe { core::mem::zeroed() };
+        assert_eq!(queue.earliest(), None);
+        assert!(queue.insert(10, callback(1)).is_some());
+        assert_eq!(queue.earliest(), Some(10));
+    }
+
+    #[test]
+    fn expires_in_deadline_order() {
+        let mut queue = AlarmQueue::<4>::new();
+        let late = queue.insert(200, callback(2)).unwrap();
+        let early = queue.insert(100, callback(1)).unwrap();
+        assert_eq!(queue.earliest(), Some(100));
+        assert_eq!(queue.pop_expired(99), None);
+        assert_eq!(queue.pop_expired(150), Some((early, callback(1))));
+        assert_eq!(queue.pop_expired(150), None);
+        assert_eq!(queue.earliest(), Some(200));
+        assert_eq!(queue.pop_expired(200), Some((late, callback(2))));
+        assert_eq!(queue.earliest(), None);
+    }
+
+    #[test]
+    fn expired_together_pop_in_deadline_order() {
+        let mut queue = AlarmQueue::<4>::new();
+        let late = queue.insert(200, callback(2)).unwrap();
+        let early = queue.insert(100, callback(1)).unwrap();
+        assert_eq!(queue.pop_expired(300), Some((early, callback(1))));
+        assert_eq!(queue.pop_expired(300), Some((late, callback(2))));
+        assert_eq!(queue.pop_expired(300), None);
+    }
+
+    #[test]
+    fn full_queue_refuses() {
+        let mut queue = AlarmQueue::<2>::new();
+        assert!(queue.insert(1, callback(1)).is_some());
+        assert!(queue.insert(2, callback(2)).is_some());
+        assert_eq!(queue.insert(3, callback(3)), None);
+    }
+
+    #[test]
+    fn cancel_removes_alarm() {
+        let mut queue = AlarmQueue::<2>::new();
+        let id = queue.insert(10, callback(1)).unwrap();
+        assert!(queue.cancel(id));
+        assert!(!queue.cancel(id));
+        assert_eq!(queue.earliest(), None);
+        assert_eq!(queue.pop_expired(u64::MAX), None);
+    }
+
+    #[test]
+    fn stale_id_does_not_cancel_reused_slot() {
+        let mut queue = AlarmQueue::<1>::new();
+        let old = queue.insert(10, callback(1)).unwrap();
+        assert_eq!(queue.pop_expired(10), Some((old, callback(1))));
+        let new = queue.insert(20, callback(2)).unwrap();
+        assert_ne!(old, new);
+        assert!(!queue.cancel(old));
+        assert_eq!(queue.earliest(), Some(20));
+        assert!(queue.cancel(new));
+    }
+}
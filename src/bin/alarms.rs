@@ -0,0 +1,61 @@
+//! Software alarm example for NXP S32Z280
+//!
+//! Runs two periodic alarms at different rates from the one Virtual Timer.
+
+#![no_std]
+#![no_main]
+
+use arm_dcc::dprintln as println;
+use arm_gic::gicv3::InterruptGroup;
+
+// pull in our start-up code
+use s32z2_rust_demo::{
+    gic::{self, Gic},
+    time::{self, AlarmId, Duration, Instant},
+};
+
+/// The entry-point to the Rust application.
+///
+/// It is called by the start-up code in `lib.rs`
+#[no_mangle]
+pub fn s32z2_main() {
+    println!("Creating GIC driver...");
+    let mut gic = unsafe { Gic::init() };
+
+    println!("Starting alarms...");
+    time::init(&mut gic, 0x31).expect("time init");
+    time::set_alarm_after(Duration::from_millis(500), fast_alarm).expect("fast alarm");
+    time::set_alarm_after(Duration::from_secs(2), slow_alarm).expect("slow alarm");
+
+    unsafe {
+        cortex_ar::interrupt::enable();
+    }
+
+    loop {
+        cortex_ar::asm::wfi();
+    }
+}
+
+/// Runs every half a second
+fn fast_alarm(_id: AlarmId) {
+    println!(
+        "- Fast alarm at {} ms",
+        Instant::now().duration_since_epoch().as_millis()
+    );
+    time::set_alarm_after(Duration::from_millis(500), fast_alarm).expect("fast alarm");
+}
+
+/// Runs every two seconds
+fn slow_alarm(_id: AlarmId) {
+    println!(
+        "- Slow alarm at {} ms",
+        Instant::now().duration_since_epoch().as_millis()
+    );
+    time::set_alarm_after(Duration::from_secs(2), slow_alarm).expect("slow alarm");
+}
+
+/// Called when the Arm core gets an IRQ
+#[cortex_r_rt::irq]
+fn irq_handler() {
+    gic::dispatch(InterruptGroup::Group1);
+}
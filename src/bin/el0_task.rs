@@ -0,0 +1,56 @@
+//! EL0 task example for NXP S32Z2
+//!
+//! Runs a task in User mode, which adds up some numbers with a system call,
+//! and then tries to touch a peripheral directly.
+
+#![no_std]
+#![no_main]
+
+use arm_dcc::dprintln as println;
+use s32z2_rust_demo::{syscall, task};
+
+/// Our system call which adds up its arguments
+const SYS_ADD: u32 = 1;
+
+/// A counter in task RAM, which the task can write to
+#[link_section = ".task"]
+static mut TASK_COUNTER: u32 = 0;
+
+/// The entry-point to the Rust application.
+///
+/// It is called by the start-up code in `lib.rs`
+#[no_mangle]
+pub fn s32z2_main() {
+    task::register(SYS_ADD, sys_add).expect("register SYS_ADD");
+
+    println!("Running the well-behaved task...");
+    let code = task::run(well_behaved, 10).expect("run task");
+    println!("Task exited with {}", code);
+
+    println!("Running the badly-behaved task...");
+    // The task takes a Data Abort, as the peripherals are ReadWriteNoEL0
+    let code = task::run(badly_behaved, 0).expect("run task");
+    println!("Task exited with {}, which it shouldn't have", code);
+}
+
+/// Add up the arguments, in the kernel
+fn sys_add(args: [u32; 4]) -> u32 {
+    args.iter().fold(0, |sum, arg| sum.wrapping_add(*arg))
+}
+
+/// A task which only uses system calls and its own RAM
+extern "C" fn well_behaved(arg: u32) -> u32 {
+    // SAFETY: only the task uses TASK_COUNTER, and only one task runs at once
+    unsafe {
+        TASK_COUNTER += 1;
+    }
+    let sum = syscall!(SYS_ADD, arg, 20, 30);
+    println!("In the task: {} + 20 + 30 = {}", arg, sum);
+    sum
+}
+
+/// A task which tries to read a peripheral
+extern "C" fn badly_behaved(_arg: u32) -> u32 {
+    // SAFETY: not safe at all - the MPU should stop us here
+    unsafe { core::ptr::read_volatile(0x4000_0000 as *const u32) }
+}
@@ -0,0 +1,54 @@
+//! Embassy example for NXP S32Z280
+//!
+//! Runs two async tasks which sleep for different amounts of time.
+
+#![no_std]
+#![no_main]
+
+use arm_dcc::dprintln as println;
+use arm_gic::gicv3::InterruptGroup;
+use embassy_time::{Duration, Instant, Timer};
+
+// pull in our start-up code
+use s32z2_rust_demo::{
+    embassy::Executor,
+    gic::{self, Gic},
+};
+
+/// Storage for our executor, which must live forever
+static mut EXECUTOR: Option<Executor> = None;
+
+/// The entry-point to the Rust application.
+///
+/// It is called by the start-up code in `lib.rs`
+#[no_mangle]
+pub fn s32z2_main() {
+    println!("Creating GIC driver...");
+    let mut gic = unsafe { Gic::init() };
+    s32z2_rust_demo::embassy::init(&mut gic, 0x31).expect("embassy init");
+
+    // Safety: we only do this once, before anything else can see it
+    let executor = unsafe {
+        let executor = &mut *core::ptr::addr_of_mut!(EXECUTOR);
+        executor.insert(Executor::new())
+    };
+    executor.run(|spawner| {
+        spawner.must_spawn(ticker("fast", Duration::from_millis(500)));
+        spawner.must_spawn(ticker("slow", Duration::from_secs(2)));
+    });
+}
+
+/// Print a message every `period`
+#[embassy_executor::task(pool_size = 2)]
+async fn ticker(name: &'static str, period: Duration) {
+    loop {
+        Timer::after(period).await;
+        println!("- {} tick at {} ms", name, Instant::now().as_millis());
+    }
+}
+
+/// Called when the Arm core gets an IRQ
+#[cortex_r_rt::irq]
+fn irq_handler() {
+    gic::dispatch(InterruptGroup::Group1);
+}
@@ -0,0 +1,78 @@
+//! FIQ example for NXP S32Z280
+//!
+//! Configures an SGI in Group 0, so it arrives as an FIQ, and services it
+//! with the default FIQ handler and the shared handler table.
+
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use arm_dcc::dprintln as println;
+use arm_gic::{
+    gicv3::{GicCpuInterface, InterruptGroup, SgiTarget, SgiTargetGroup},
+    IntId,
+};
+
+// pull in our start-up code
+use s32z2_rust_demo::gic::{self, Gic, InterruptConfig};
+
+/// How many FIQs we have handled
+static FIQ_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// The entry-point to the Rust application.
+///
+/// It is called by the start-up code in `lib.rs`
+#[no_mangle]
+pub fn s32z2_main() {
+    println!("Creating GIC driver...");
+    let mut gic = unsafe { Gic::init() };
+
+    // An SGI for Core 0, in Group 0 so it is signalled as an FIQ
+    let sgi_intid = IntId::sgi(5);
+    gic::register_handler(sgi_intid, handle_sgi_fiq).expect("register SGI handler");
+    gic.configure(sgi_intid, &InterruptConfig::fiq(0x20))
+        .expect("configure SGI int");
+
+    println!("Enabling FIQs...");
+    // Safety: we are not in a critical section, and our FIQ handler only
+    // touches an atomic
+    unsafe {
+        gic::enable_fiq();
+    }
+
+    for _ in 0..3 {
+        GicCpuInterface::send_sgi(
+            sgi_intid,
+            SgiTarget::List {
+                affinity3: 0,
+                affinity2: 0,
+                affinity1: 0,
+                target_list: 0b1,
+            },
+            SgiTargetGroup::Group0,
+        )
+        .expect("send SGI");
+    }
+
+    println!(
+        "Handled {} FIQs ({} spurious)",
+        FIQ_COUNT.load(Ordering::Relaxed),
+        gic::spurious_count()
+    );
+
+    loop {
+        cortex_ar::asm::nop();
+    }
+}
+
+/// Called when the Arm core gets an IRQ - we don't expect any
+#[cortex_r_rt::irq]
+fn irq_handler() {
+    gic::dispatch(InterruptGroup::Group1);
+}
+
+/// Run, in FIQ mode, when the SGI is fired
+fn handle_sgi_fiq(_int_id: IntId) {
+    FIQ_COUNT.fetch_add(1, Ordering::Relaxed);
+}
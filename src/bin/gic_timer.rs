@@ -3,23 +3,15 @@
 #![no_std]
 #![no_main]
 
-use core::ptr::NonNull;
-
 use arm_dcc::dprintln as println;
 use arm_gic::{
-    gicv3::{GicCpuInterface, GicV3, Group, InterruptGroup, SgiTarget, SgiTargetGroup},
-    IntId, UniqueMmioPointer,
+    gicv3::{GicCpuInterface, InterruptGroup, SgiTarget, SgiTargetGroup},
+    IntId,
 };
 use cortex_ar::generic_timer::{El1VirtualTimer, GenericTimer};
 
 // pull in our start-up code
-use s32z2_rust_demo as _;
-
-/// Offset from PERIPHBASE for GIC Distributor
-const GICD_BASE_OFFSET: usize = 0x0000_0000usize;
-
-/// Offset from PERIPHBASE for the first GIC Redistributor
-const GICR_BASE_OFFSET: usize = 0x0010_0000usize;
+use s32z2_rust_demo::gic::{self, Gic, InterruptConfig};
 
 /// The PPI for the virutal timer, according to the Cortex-R52 Reference Manual
 ///
@@ -34,40 +26,20 @@ const SGI_ID: IntId = IntId::sgi(3);
 /// It is called by the start-up code in `lib.rs`
 #[no_mangle]
 pub fn s32z2_main() {
-    // Get the GIC address by reading CBAR
-    let periphbase = cortex_ar::register::ImpCbar::read().periphbase();
-    println!("Found PERIPHBASE {:010p}", periphbase);
-    let gicd_base = periphbase.wrapping_byte_add(GICD_BASE_OFFSET);
-    let gicr_base = periphbase.wrapping_byte_add(GICR_BASE_OFFSET);
-
     // Initialise the GIC.
-    println!(
-        "Creating GIC driver @ {:010p} / {:010p}",
-        gicd_base, gicr_base
-    );
-    let gicd = unsafe { UniqueMmioPointer::new(NonNull::new(gicd_base.cast()).unwrap()) };
-    let gicr_base = NonNull::new(gicr_base.cast()).unwrap();
-    let mut gic: GicV3 = unsafe { GicV3::new(gicd, gicr_base, 1, false) };
-    println!("Calling git.setup(0)");
-    gic.setup(0);
-    GicCpuInterface::set_priority_mask(0x80);
+    println!("Creating GIC driver...");
+    let mut gic = unsafe { Gic::init() };
 
     // Configure a Software Generated Interrupt for Core 0
     println!("Configure SGI...");
-    gic.set_interrupt_priority(SGI_ID, Some(0), 0x31)
-        .expect("SGI set_interrupt_priority");
-    gic.set_group(SGI_ID, Some(0), Group::Group1NS)
-        .expect("SGI set_group");
-    gic.enable_interrupt(SGI_ID, Some(0), true)
-        .expect("SGI enable_interrupt");
+    gic::register_handler(SGI_ID, handle_sgi_irq).expect("SGI register");
+    gic.configure(SGI_ID, &InterruptConfig::new(0x31))
+        .expect("SGI configure");
 
     println!("Configure Timer Interrupt...");
-    gic.set_interrupt_priority(VIRTUAL_TIMER_PPI, Some(0), 0x31)
-        .expect("Timer set_interrupt_priority");
-    gic.set_group(VIRTUAL_TIMER_PPI, Some(0), Group::Group1NS)
-        .expect("Timer set_group");
-    gic.enable_interrupt(VIRTUAL_TIMER_PPI, Some(0), true)
-        .expect("Timer enable_interrupt");
+    gic::register_handler(VIRTUAL_TIMER_PPI, handle_timer_irq).expect("Timer register");
+    gic.configure(VIRTUAL_TIMER_PPI, &InterruptConfig::new(0x31))
+        .expect("Timer configure");
 
     let mut vgt = unsafe { El1VirtualTimer::new() };
     vgt.enable(true);
@@ -114,21 +86,12 @@ fn dump_cpsr() {
 #[cortex_r_rt::irq]
 fn irq_handler() {
     println!("> IRQ");
-    while let Some(int_id) = GicCpuInterface::get_and_acknowledge_interrupt(InterruptGroup::Group1)
-    {
-        println!("- IRQ handle {:?}", int_id);
-        if int_id == VIRTUAL_TIMER_PPI {
-            handle_timer_irq();
-        } else if int_id == SGI_ID {
-            handle_sgi_irq();
-        }
-        GicCpuInterface::end_interrupt(int_id, InterruptGroup::Group1);
-    }
+    gic::dispatch(InterruptGroup::Group1);
     println!("< IRQ");
 }
 
 /// Run when the timer IRQ fires
-fn handle_timer_irq() {
+fn handle_timer_irq(_int_id: IntId) {
     println!("- Timer fired - resetting");
     // trigger a timer in 1 second
     let mut vgt = unsafe { El1VirtualTimer::new() };
@@ -136,6 +99,6 @@ fn handle_timer_irq() {
 }
 
 /// Run when the SGI is fired
-fn handle_sgi_irq() {
+fn handle_sgi_irq(_int_id: IntId) {
     println!("- SGI fired");
 }
@@ -0,0 +1,89 @@
+//! EL2 monitor example for NXP S32Z2
+//!
+//! Runs the application as the primary guest, plus an untrusted guest which
+//! can only reach its own stack. The guests take turns, and the untrusted one
+//! is stopped when it tries to touch a peripheral.
+
+#![no_std]
+#![no_main]
+
+use arm_dcc::dprintln as println;
+use cortex_ar::pmsav8::{El2AccessPerms, El2Region, El2Shareability};
+
+use s32z2_rust_demo::hyp::{self, Entry, Guest};
+use s32z2_rust_demo::mpu::MPU_MAIR_INDEX_DATA;
+
+/// How big the untrusted guest's stack is
+const STACK_SIZE: usize = 4096;
+
+/// A stack, aligned so it can have an MPU region to itself
+#[repr(C, align(64))]
+struct Stack([u8; STACK_SIZE]);
+
+/// The untrusted guest's stack
+///
+/// It lives in `.guest.bss`, as the primary guest can reach the rest of Data
+/// RAM.
+#[link_section = ".guest.bss"]
+static mut UNTRUSTED_STACK: Stack = Stack([0; STACK_SIZE]);
+
+/// Sets up the guests, in EL2
+///
+/// It is called by the monitor in `hyp.rs`, before any guest runs.
+#[no_mangle]
+pub fn s32z2_hyp_main() {
+    // This only works in EL2
+    println!("{:?}", cortex_ar::register::Hvbar::read());
+
+    hyp::add_guest(&Guest {
+        entry: Entry::Primary,
+        regions: &hyp::primary_regions(),
+    })
+    .expect("Primary guest");
+
+    let stack = core::ptr::addr_of_mut!(UNTRUSTED_STACK) as usize;
+    hyp::add_guest(&Guest {
+        entry: Entry::Function {
+            function: untrusted_main,
+            stack_top: stack + STACK_SIZE,
+        },
+        regions: &[El2Region {
+            range: (stack as *mut u8)..=((stack + STACK_SIZE - 1) as *mut u8),
+            shareability: El2Shareability::InnerShareable,
+            access: El2AccessPerms::ReadWrite,
+            no_exec: true,
+            mair: MPU_MAIR_INDEX_DATA,
+            enable: true,
+        }],
+    })
+    .expect("Untrusted guest");
+}
+
+/// The entry-point to the Rust application, in the primary guest.
+///
+/// It is called by the start-up code in `lib.rs`
+#[no_mangle]
+pub fn s32z2_main() {
+    for count in 0..5 {
+        println!("Primary guest, turn {}", count);
+        hyp::yield_now();
+    }
+    println!("Primary guest done");
+    hyp::exit();
+}
+
+/// The entry-point for the untrusted guest
+extern "C" fn untrusted_main() -> ! {
+    for count in 0..3 {
+        println!("Untrusted guest, turn {}", count);
+        hyp::yield_now();
+    }
+    println!("Untrusted guest reading a peripheral...");
+    // SAFETY: not safe at all - the monitor should stop us here
+    let value = unsafe { core::ptr::read_volatile(0x4000_0000 as *const u32) };
+    println!(
+        "Untrusted guest read {:#010x}, which it shouldn't have",
+        value
+    );
+    hyp::exit();
+}
@@ -0,0 +1,92 @@
+//! Multi-core example for NXP S32Z280
+//!
+//! Needs RTU0 in split-lock mode, so that all four cores can run.
+
+#![no_std]
+#![no_main]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use arm_dcc::dprintln as println;
+use s32z2_rust_demo::mc_me::Core;
+
+/// How many times each core has gone around its loop
+///
+/// In `.shared`, so every core sees the others' updates without any cache
+/// maintenance. Each core only writes its own counter, with plain loads and
+/// stores, as exclusive accesses may not be supported in non-cacheable
+/// memory (see [`s32z2_rust_demo::ring_buffer`]).
+#[link_section = ".shared"]
+static COUNTERS: [AtomicU32; 4] = [const { AtomicU32::new(0) }; 4];
+
+/// Add `step` to a core's counter
+///
+/// Only core `core` may call this, so the load and store can't race.
+fn bump(core: usize, step: u32) {
+    let counter = &COUNTERS[core];
+    counter.store(
+        counter.load(Ordering::Relaxed).wrapping_add(step),
+        Ordering::Relaxed,
+    );
+}
+
+/// A static that only core 1 uses, in core 1's own Data RAM
+#[link_section = ".core1.data"]
+static mut CORE1_STEP: u32 = 1;
+
+/// The entry-point to the Rust application.
+///
+/// It is called by the start-up code in `lib.rs`
+#[no_mangle]
+pub fn s32z2_main() {
+    let mut mc_me = unsafe { s32z2_rust_demo::mc_me::mc_me() };
+    for core in [Core::Core1, Core::Core2, Core::Core3] {
+        if s32z2_rust_demo::mc_me::core_running(
+            &mut mc_me,
+            s32z2_rust_demo::mc_me::Partition::Rtu0,
+            core,
+        ) {
+            println!("{:?} is already running", core);
+        } else {
+            println!("Starting {:?}", core);
+            unsafe { s32z2_rust_demo::start_core(core) }.expect("start core");
+        }
+    }
+
+    loop {
+        bump(0, 1);
+        println!(
+            "Counters: {} {} {} {}",
+            COUNTERS[0].load(Ordering::Relaxed),
+            COUNTERS[1].load(Ordering::Relaxed),
+            COUNTERS[2].load(Ordering::Relaxed),
+            COUNTERS[3].load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// The entry-point for Core 1
+#[no_mangle]
+pub fn s32z2_main_core1() {
+    loop {
+        // SAFETY: Only this core uses CORE1_STEP
+        let step = unsafe { CORE1_STEP };
+        bump(1, step);
+    }
+}
+
+/// The entry-point for Core 2
+#[no_mangle]
+pub fn s32z2_main_core2() {
+    loop {
+        bump(2, 1);
+    }
+}
+
+/// The entry-point for Core 3
+#[no_mangle]
+pub fn s32z2_main_core3() {
+    loop {
+        bump(3, 1);
+    }
+}
@@ -1,10 +1,103 @@
 //! Clock configuration code for the S32Z2
 //!
-//! Programs the *DFS* (Digital Frequency Synthesizer).
+//! Programs the *PLL* (Phase Locked Loop) and *DFS* (Digital Frequency
+//! Synthesizer) blocks, and works out the Generic Timer frequency.
 
 use arbitrary_int::{u15, u3, u6};
 use arm_dcc::dprintln as println;
 
+/// Base address of the CORE_PLL
+const CORE_PLL_BASE: usize = 0x4021_0000;
+
+/// Base address of the PERIPH_PLL
+const PERIPH_PLL_BASE: usize = 0x4022_0000;
+
+/// Base address of the CORE_DFS
+const CORE_DFS_BASE: usize = 0x4026_0000;
+
+/// Base address of the PERIPH_DFS
+const PERIPH_DFS_BASE: usize = 0x4027_0000;
+
+/// Frequency of the external Fast Crystal Oscillator (FXOSC) on the EVB
+pub const FXOSC_HZ: u32 = 40_000_000;
+
+/// Frequency of the Fast Internal RC oscillator (FIRC)
+pub const FIRC_HZ: u32 = 48_000_000;
+
+/// The MC_CGM divider between FXOSC and the system counter, as written to
+/// the register, so zero divides by one
+///
+/// Nothing here reprograms it, so this is its reset value.
+pub const COUNTER_CGM_DIV: u8 = 0;
+
+/// The RTU's `CNTDIV` system counter divider, which divides by `CNTDIV + 1`
+///
+/// Nothing here reprograms it, so this is its reset value of 4.
+pub const CNTDIV: u8 = 4;
+
+/// Frequency of the system counter which drives the Generic Timer
+///
+/// Worked out from [`FXOSC_HZ`], [`COUNTER_CGM_DIV`] and [`CNTDIV`] at compile
+/// time, as the start-up code writes it to `CNTFRQ` before the MPU is set up,
+/// when it can't read the clock tree. [`check_counter_hz`] measures the timer
+/// to make sure.
+pub const COUNTER_HZ: u32 = counter_hz(FXOSC_HZ, COUNTER_CGM_DIV, CNTDIV);
+
+/// How far the measured Generic Timer frequency can be from the programmed
+/// one before we complain, in parts per thousand
+#[cfg(target_arch = "arm")]
+pub const COUNTER_TOLERANCE_PPT: u64 = 20;
+
+/// How many CPU cycles to spend measuring the Generic Timer frequency
+#[cfg(target_arch = "arm")]
+pub const COUNTER_MEASURE_CYCLES: u32 = 8_000_000;
+
+/// The CORE_DFS port that clocks the RTU cores
+const RTU_CORE_DFS_PORT: usize = 0;
+
+/// The MC_CGM instance with the mux that clocks the RTU cores
+const RTU_CORE_CGM: crate::mc_cgm::Instance = crate::mc_cgm::Instance::Cgm1;
+
+/// The MC_CGM mux that clocks the RTU cores, from [`RTU_CORE_DFS_PORT`]
+const RTU_CORE_MUX: usize = 0;
+
+/// The denominator of the PLL fractional multiplier
+const PLL_MFN_DENOMINATOR: u64 = 18432;
+
+/// The denominator of the DFS fractional divider
+const DFS_MFN_DENOMINATOR: u64 = 36;
+
+// The PLLDIG operating limits used by the clock solver. These have not yet
+// been checked against the PLLDIG chapter of the S32Z2 Reference Manual - cite
+// the table here when they are.
+
+/// Lowest legal PLL VCO frequency
+pub const VCO_MIN_HZ: u64 = 1_300_000_000;
+
+/// Highest legal PLL VCO frequency
+pub const VCO_MAX_HZ: u64 = 5_000_000_000;
+
+/// Lowest legal PLL phase detector frequency (reference clock / `rdiv`)
+pub const PFD_MIN_HZ: u64 = 10_000_000;
+
+/// Highest legal PLL phase detector frequency (reference clock / `rdiv`)
+pub const PFD_MAX_HZ: u64 = 40_000_000;
+
+/// Lowest legal integer part of the PLL loop multiplier
+const PLL_MFI_MIN: u64 = 10;
+
+/// Highest legal integer part of the PLL loop multiplier
+const PLL_MFI_MAX: u64 = 150;
+
+/// Lowest legal integer part of a DFS divider
+const DFS_MFI_MIN: u64 = 1;
+
+/// How many times we poll a status flag before giving up
+const POLL_LIMIT: u32 = 1_000_000;
+
+/// Enable extra debug output over DCC
+static VERBOSE_DEBUGGING: bool = false;
+
 /// The DFS Peripheral
 #[derive(derive_mmio::Mmio)]
 #[repr(C)]
@@ -124,8 +217,8 @@ pub struct PllDig {
     /// PLL Clock Multiplexer, offset: 0x20
     pllclkmux: PllDigClkMux,
     _reserved1: [u32; 23],
-    /// PLL Dividers
-    pllodiv: [u32; 6],
+    /// PLL Output Dividers, offset: 0x80
+    pllodiv: [PllDigOdiv; 6],
 }
 
 /// The PLL Status Register
@@ -189,6 +282,23 @@ impl core::fmt::Debug for PllDigFd {
     }
 }
 
+/// A PLL Output Divider Register
+#[bitbybit::bitfield(u32)]
+pub struct PllDigOdiv {
+    /// If true, the divider (and hence the output) is enabled
+    #[bit(31, rw)]
+    de: bool,
+    /// Division value, minus one
+    #[bits(16..=23, rw)]
+    div: u8,
+}
+
+impl core::fmt::Debug for PllDigOdiv {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "PllDigOdiv(de={}, div={})", self.de(), self.div())
+    }
+}
+
 /// The PLL Clock Mux
 #[bitbybit::bitfield(u32)]
 pub struct PllDigClkMux {
@@ -209,22 +319,836 @@ impl core::fmt::Debug for PllDigClkMux {
     }
 }
 
-/// Configure the PLLs on the S32Z2
+/// Errors that can occur when configuring the clocks
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The PLL did not report lock in time
+    PllLockTimeout,
+    /// A DFS port did not report lock in time
+    DfsLockTimeout {
+        /// The port which failed to lock
+        port: usize,
+    },
+    /// A clock request did not ask for any frequencies
+    NothingRequested,
+    /// No legal PLL and DFS settings produce the requested frequencies
+    NoSolution,
+    /// Could not move the RTU cores to FIRC and back whilst the CORE_DFS was
+    /// reprogrammed
+    CoreClockSwitch(crate::mc_cgm::Error),
+    /// The Generic Timer frequency in `CNTFRQ` does not match [`COUNTER_HZ`]
+    /// or a measurement of the timer against the CPU clock
+    CounterMismatch {
+        /// The value in `CNTFRQ`
+        programmed: u32,
+        /// The value we expected, [`COUNTER_HZ`]
+        expected: u32,
+        /// The value measured against the CPU clock, if we know it
+        measured: Option<u32>,
+    },
+}
+
+/// Selects which reference clock feeds a PLL
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PllSource {
+    /// The Fast Internal RC oscillator
+    Firc,
+    /// The external Fast Crystal Oscillator
+    Fxosc,
+}
+
+impl PllSource {
+    /// The frequency of this reference clock, in Hz
+    pub const fn frequency_hz(self) -> u32 {
+        match self {
+            PllSource::Firc => FIRC_HZ,
+            PllSource::Fxosc => FXOSC_HZ,
+        }
+    }
+}
+
+/// Settings for a PLL
+///
+/// The VCO runs at `f_ref / rdiv * (mfi + mfn / 18432)`, and each output
+/// runs at `f_vco / (odiv + 1)`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PllConfig {
+    /// Which reference clock to use
+    pub source: PllSource,
+    /// Reference clock pre-divider (zero also means divide-by-one)
+    pub rdiv: u3,
+    /// Integer part of the loop multiplier
+    pub mfi: u8,
+    /// Numerator of the fractional part of the loop multiplier
+    pub mfn: u15,
+    /// Output divider values (minus one), or `None` to disable that output
+    pub odiv: [Option<u8>; 6],
+}
+
+/// Settings for one DFS output port
 ///
-/// Acutally it seems the PLLs are already running, so this actually
-/// just prints the configuration.
-pub fn configure_pll() {
-    let mut ip_core_dfs = unsafe { Dfs::new_mmio_at(0x4026_0000) };
-    let mut ip_periph_dfs = unsafe { Dfs::new_mmio_at(0x4027_0000) };
-    let mut ip_core_pll = unsafe { PllDig::new_mmio_at(0x4021_0000) };
-    let mut ip_periph_pll = unsafe { PllDig::new_mmio_at(0x4022_0000) };
+/// The port runs at `f_vco / (2 * (mfi + mfn / 36))`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DfsPortConfig {
+    /// Integer part of division value
+    pub mfi: u8,
+    /// Numerator of fractional part of division value
+    pub mfn: u6,
+}
+
+/// Settings for a DFS
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DfsConfig {
+    /// Settings for each port, or `None` to hold that port in reset
+    pub ports: [Option<DfsPortConfig>; 6],
+}
 
-    print_clock_setup("core", &mut ip_core_dfs, &mut ip_core_pll);
-    print_clock_setup("periph", &mut ip_periph_dfs, &mut ip_periph_pll);
+/// Settings for the CORE and PERIPH clock trees
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ClockConfig {
+    /// Settings for the CORE_PLL
+    pub core_pll: PllConfig,
+    /// Settings for the CORE_DFS, which is fed by the CORE_PLL
+    pub core_dfs: DfsConfig,
+    /// Settings for the PERIPH_PLL
+    pub periph_pll: PllConfig,
+    /// Settings for the PERIPH_DFS, which is fed by the PERIPH_PLL
+    pub periph_dfs: DfsConfig,
+}
+
+impl ClockConfig {
+    /// The clock configuration this demo expects to run with.
+    ///
+    /// Both PLLs run from the 40 MHz crystal with a 2 GHz VCO. CORE_DFS port
+    /// 0 gives 800 MHz for the Cortex-R52 cores.
+    pub const DEFAULT: ClockConfig = ClockConfig {
+        core_pll: PllConfig {
+            source: PllSource::Fxosc,
+            rdiv: u3::new(1),
+            mfi: 50,
+            mfn: u15::new(0),
+            odiv: [Some(1), Some(1), None, None, None, None],
+        },
+        core_dfs: DfsConfig {
+            ports: [
+                Some(DfsPortConfig {
+                    mfi: 1,
+                    mfn: u6::new(9),
+                }),
+                Some(DfsPortConfig {
+                    mfi: 2,
+                    mfn: u6::new(18),
+                }),
+                None,
+                None,
+                None,
+                None,
+            ],
+        },
+        periph_pll: PllConfig {
+            source: PllSource::Fxosc,
+            rdiv: u3::new(1),
+            mfi: 50,
+            mfn: u15::new(0),
+            odiv: [Some(19), Some(3), Some(4), Some(9), None, None],
+        },
+        periph_dfs: DfsConfig {
+            ports: [
+                Some(DfsPortConfig {
+                    mfi: 2,
+                    mfn: u6::new(18),
+                }),
+                None,
+                None,
+                None,
+                None,
+                None,
+            ],
+        },
+    };
+}
+
+impl ClockConfig {
+    /// Calculate the frequencies this configuration will produce
+    pub const fn clocks(&self) -> Clocks {
+        Clocks {
+            core: PllClocks::from_config(&self.core_pll, &self.core_dfs),
+            periph: PllClocks::from_config(&self.periph_pll, &self.periph_dfs),
+        }
+    }
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// The frequencies wanted from the CORE and PERIPH clock trees
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ClockRequest {
+    /// What we want from the CORE_PLL and CORE_DFS
+    pub core: PllRequest,
+    /// What we want from the PERIPH_PLL and PERIPH_DFS
+    pub periph: PllRequest,
+}
+
+impl ClockRequest {
+    /// Find PLL and DFS settings which produce exactly the requested
+    /// frequencies
+    pub const fn solve(&self) -> Result<ClockConfig, Error> {
+        let (core_pll, core_dfs) = match self.core.solve() {
+            Ok(config) => config,
+            Err(e) => return Err(e),
+        };
+        let (periph_pll, periph_dfs) = match self.periph.solve() {
+            Ok(config) => config,
+            Err(e) => return Err(e),
+        };
+        Ok(ClockConfig {
+            core_pll,
+            core_dfs,
+            periph_pll,
+            periph_dfs,
+        })
+    }
+}
+
+impl ClockConfig {
+    /// Find the settings for a clock request, panicking if there are none
+    ///
+    /// Use this to initialise a `const`, and an impossible request becomes a
+    /// compile-time error.
+    pub const fn from_request(request: &ClockRequest) -> ClockConfig {
+        match request.solve() {
+            Ok(config) => config,
+            Err(Error::NothingRequested) => panic!("Clock request is empty"),
+            Err(_) => panic!("No legal clock configuration for this request"),
+        }
+    }
+}
+
+/// The frequencies wanted from one PLL and the DFS it feeds
+///
+/// All values are in Hz, and `None` means the output is not needed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PllRequest {
+    /// Which reference clock to use
+    pub source: PllSource,
+    /// The wanted PLL outputs (PHI0 to PHI5)
+    pub outputs: [Option<u32>; 6],
+    /// The wanted DFS output ports
+    pub dfs_ports: [Option<u32>; 6],
+}
+
+impl PllRequest {
+    /// Find PLL and DFS settings which produce exactly the requested
+    /// frequencies
+    ///
+    /// Integer-mode PLL settings are preferred over fractional ones, and
+    /// otherwise the lowest legal VCO frequency wins.
+    pub const fn solve(&self) -> Result<(PllConfig, DfsConfig), Error> {
+        // Every legal VCO frequency is some multiple of the first output we
+        // want, so we only need to try those.
+        let Some((target, is_dfs)) = self.first_request() else {
+            return Err(Error::NothingRequested);
+        };
+        if target == 0 {
+            return Err(Error::NoSolution);
+        }
+        let target = target as u64;
+        // For a PLL output, VCO = target * k. For a DFS port, VCO = target * k / 18.
+        let (scale, max_k) = if is_dfs {
+            (DFS_MFN_DENOMINATOR / 2, 255 * DFS_MFN_DENOMINATOR + 35)
+        } else {
+            (1, 256)
+        };
+        let mut fractional = false;
+        loop {
+            let mut k = (VCO_MIN_HZ * scale).div_ceil(target);
+            while k <= max_k && (target * k) / scale <= VCO_MAX_HZ {
+                if (target * k) % scale == 0 {
+                    let vco_hz = (target * k) / scale;
+                    let mut rdiv = 1;
+                    while rdiv <= 7 {
+                        if let Some(pll) = self.pll_for_vco(vco_hz, rdiv, fractional) {
+                            if let Some(dfs) = self.dfs_for_vco(vco_hz) {
+                                return Ok((pll, dfs));
+                            }
+                        }
+                        rdiv += 1;
+                    }
+                }
+                k += 1;
+            }
+            if fractional {
+                return Err(Error::NoSolution);
+            }
+            fractional = true;
+        }
+    }
+
+    /// Get the first requested frequency, and whether it is a DFS port
+    const fn first_request(&self) -> Option<(u32, bool)> {
+        let mut idx = 0;
+        while idx < 6 {
+            if let Some(hz) = self.outputs[idx] {
+                return Some((hz, false));
+            }
+            idx += 1;
+        }
+        let mut idx = 0;
+        while idx < 6 {
+            if let Some(hz) = self.dfs_ports[idx] {
+                return Some((hz, true));
+            }
+            idx += 1;
+        }
+        None
+    }
+
+    /// Find PLL settings giving this VCO frequency and all the PLL outputs
+    const fn pll_for_vco(&self, vco_hz: u64, rdiv: u64, fractional: bool) -> Option<PllConfig> {
+        let reference_hz = self.source.frequency_hz() as u64;
+        if reference_hz < PFD_MIN_HZ * rdiv || reference_hz > PFD_MAX_HZ * rdiv {
+            return None;
+        }
+        // multiplier = mfi * 18432 + mfn
+        let numerator = vco_hz * rdiv * PLL_MFN_DENOMINATOR;
+        if numerator % reference_hz != 0 {
+            return None;
+        }
+        let multiplier = numerator / reference_hz;
+        let mfi = multiplier / PLL_MFN_DENOMINATOR;
+        let mfn = multiplier % PLL_MFN_DENOMINATOR;
+        if mfi < PLL_MFI_MIN || mfi > PLL_MFI_MAX || (mfn != 0 && !fractional) {
+            return None;
+        }
+
+        let mut odiv = [None; 6];
+        let mut idx = 0;
+        while idx < 6 {
+            if let Some(hz) = self.outputs[idx] {
+                let hz = hz as u64;
+                if hz == 0 || vco_hz % hz != 0 {
+                    return None;
+                }
+                let divider = vco_hz / hz;
+                if divider < 1 || divider > 256 {
+                    return None;
+                }
+                odiv[idx] = Some((divider - 1) as u8);
+            }
+            idx += 1;
+        }
+
+        Some(PllConfig {
+            source: self.source,
+            rdiv: u3::new(rdiv as u8),
+            mfi: mfi as u8,
+            mfn: u15::new(mfn as u16),
+            odiv,
+        })
+    }
+
+    /// Find DFS settings giving all the DFS ports from this VCO frequency
+    const fn dfs_for_vco(&self, vco_hz: u64) -> Option<DfsConfig> {
+        let mut ports = [None; 6];
+        let mut idx = 0;
+        while idx < 6 {
+            if let Some(hz) = self.dfs_ports[idx] {
+                let hz = hz as u64;
+                // mfi * 36 + mfn = vco / (2 * hz) * 36
+                let numerator = vco_hz * (DFS_MFN_DENOMINATOR / 2);
+                if hz == 0 || numerator % hz != 0 {
+                    return None;
+                }
+                let divider = numerator / hz;
+                let mfi = divider / DFS_MFN_DENOMINATOR;
+                let mfn = divider % DFS_MFN_DENOMINATOR;
+                if mfi < DFS_MFI_MIN || mfi > 255 {
+                    return None;
+                }
+                ports[idx] = Some(DfsPortConfig {
+                    mfi: mfi as u8,
+                    mfn: u6::new(mfn as u8),
+                });
+            }
+            idx += 1;
+        }
+        Some(DfsConfig { ports })
+    }
+}
+
+/// The frequencies produced by the CORE and PERIPH clock trees
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Clocks {
+    /// The CORE_PLL and CORE_DFS
+    pub core: PllClocks,
+    /// The PERIPH_PLL and PERIPH_DFS
+    pub periph: PllClocks,
+}
+
+impl Clocks {
+    /// Work out the clock frequencies from the current PLL and DFS settings
+    pub fn read() -> Clocks {
+        // SAFETY: We only read from these registers, which has no side-effects
+        let (mut core_dfs, mut periph_dfs, mut core_pll, mut periph_pll) = unsafe {
+            (
+                Dfs::new_mmio_at(CORE_DFS_BASE),
+                Dfs::new_mmio_at(PERIPH_DFS_BASE),
+                PllDig::new_mmio_at(CORE_PLL_BASE),
+                PllDig::new_mmio_at(PERIPH_PLL_BASE),
+            )
+        };
+        Clocks {
+            core: PllClocks::read(&mut core_pll, &mut core_dfs),
+            periph: PllClocks::read(&mut periph_pll, &mut periph_dfs),
+        }
+    }
+
+    /// The clock for the RTU Cortex-R52 cores
+    pub fn rtu_core_hz(&self) -> Option<u32> {
+        self.core.dfs_ports[RTU_CORE_DFS_PORT]
+    }
+}
+
+/// The frequencies produced by one PLL and the DFS it feeds
+///
+/// All values are in Hz. Outputs which are disabled, or in reset, are
+/// `None`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PllClocks {
+    /// The reference clock going into the PLL
+    pub reference_hz: u32,
+    /// The PLL's VCO, which feeds both the output dividers and the DFS
+    pub vco_hz: u64,
+    /// The PLL output dividers (PHI0 to PHI5)
+    pub outputs: [Option<u32>; 6],
+    /// The DFS output ports
+    pub dfs_ports: [Option<u32>; 6],
+}
+
+impl PllClocks {
+    /// Calculate the frequencies a PLL and DFS configuration will produce
+    pub const fn from_config(pll: &PllConfig, dfs: &DfsConfig) -> PllClocks {
+        let reference_hz = pll.source.frequency_hz();
+        let vco_hz = vco_hz(reference_hz, pll.rdiv, pll.mfi, pll.mfn);
+        let mut outputs = [None; 6];
+        let mut dfs_ports = [None; 6];
+        let mut idx = 0;
+        while idx < 6 {
+            if let Some(div) = pll.odiv[idx] {
+                outputs[idx] = Some(pll_output_hz(vco_hz, div));
+            }
+            if let Some(port) = dfs.ports[idx] {
+                dfs_ports[idx] = Some(dfs_port_hz(vco_hz, port.mfi, port.mfn));
+            }
+            idx += 1;
+        }
+        PllClocks {
+            reference_hz,
+            vco_hz,
+            outputs,
+            dfs_ports,
+        }
+    }
+
+    /// Work out the frequencies from the registers of a PLL and DFS
+    fn read(pll: &mut MmioPllDig, dfs: &mut MmioDfs) -> PllClocks {
+        let reference_hz = if pll.read_pllclkmux().select_fxosc() {
+            FXOSC_HZ
+        } else {
+            FIRC_HZ
+        };
+        let running = !pll.read_pllcr().pd() && pll.read_pllsr().locked();
+        let dv = pll.read_plldv();
+        let fd = pll.read_pllfd();
+        let mfn = if fd.sdmen() { fd.mfn() } else { u15::new(0) };
+        let vco_hz = if running {
+            vco_hz(reference_hz, dv.rdiv(), dv.mfi(), mfn)
+        } else {
+            0
+        };
+
+        let mut outputs = [None; 6];
+        for (idx, output) in outputs.iter_mut().enumerate() {
+            if let Ok(odiv) = pll.read_pllodiv(idx) {
+                if running && odiv.de() {
+                    *output = Some(pll_output_hz(vco_hz, odiv.div()));
+                }
+            }
+        }
+
+        let dfs_running = running && !dfs.read_ctl().in_reset();
+        let reset = dfs.read_portreset().raw_value();
+        let mut dfs_ports = [None; 6];
+        for (idx, port) in dfs_ports.iter_mut().enumerate() {
+            if let Ok(dv) = dfs.read_dvports(idx) {
+                if dfs_running && (reset & (1 << idx)) == 0 {
+                    *port = Some(dfs_port_hz(vco_hz, dv.mfi(), dv.mfn()));
+                }
+            }
+        }
+
+        PllClocks {
+            reference_hz,
+            vco_hz,
+            outputs,
+            dfs_ports,
+        }
+    }
+}
+
+/// Calculate the VCO frequency of a PLL, in Hz
+///
+/// This is `reference_hz / rdiv * (mfi + mfn / 18432)`, where an `rdiv` of
+/// zero divides by one. Pass an `mfn` of zero if the sigma-delta modulator is
+/// disabled.
+pub const fn vco_hz(reference_hz: u32, rdiv: u3, mfi: u8, mfn: u15) -> u64 {
+    let rdiv = if rdiv.value() == 0 {
+        1
+    } else {
+        rdiv.value() as u64
+    };
+    let multiplier = (mfi as u64 * PLL_MFN_DENOMINATOR) + mfn.value() as u64;
+    (reference_hz as u64 * multiplier) / (rdiv * PLL_MFN_DENOMINATOR)
+}
+
+/// Calculate the frequency of a PLL output divider, in Hz
+///
+/// The `div` value is as written to the register, so the VCO is divided by
+/// `div + 1`.
+pub const fn pll_output_hz(vco_hz: u64, div: u8) -> u32 {
+    (vco_hz / (div as u64 + 1)) as u32
+}
+
+/// Calculate the frequency of a DFS output port, in Hz
+///
+/// This is `vco_hz / (2 * (mfi + mfn / 36))`. A zero divider is not valid
+/// and gives zero.
+pub const fn dfs_port_hz(vco_hz: u64, mfi: u8, mfn: u6) -> u32 {
+    let divider = 2 * ((mfi as u64 * DFS_MFN_DENOMINATOR) + mfn.value() as u64);
+    match (vco_hz * DFS_MFN_DENOMINATOR).checked_div(divider) {
+        Some(hz) => hz as u32,
+        None => 0,
+    }
+}
+
+/// Configure the PLLs and DFSs on the S32Z2
+///
+/// Any PLL which is already locked with the requested settings is left
+/// alone, as is its DFS if every port already matches. Otherwise the PLL is
+/// powered down, reprogrammed and relocked, and then the DFS ports are
+/// brought back up.
+///
+/// The RTU cores run from the CORE_DFS, so if that tree needs changing, their
+/// clock mux is switched to FIRC first, and back to whatever it had selected
+/// once the CORE_DFS ports have locked. If reprogramming fails, the cores are
+/// left running from FIRC.
+///
+/// Returns the resulting clock frequencies, as read back from the hardware.
+///
+/// # Safety
+///
+/// If a PLL or DFS needs reprogramming, its outputs will stop for a while.
+/// Only the calling core may be running in the RTU, and nothing else may be
+/// clocked from the outputs being reprogrammed.
+pub unsafe fn configure_pll(config: &ClockConfig) -> Result<Clocks, Error> {
+    let mut ip_core_dfs = unsafe { Dfs::new_mmio_at(CORE_DFS_BASE) };
+    let mut ip_periph_dfs = unsafe { Dfs::new_mmio_at(PERIPH_DFS_BASE) };
+    let mut ip_core_pll = unsafe { PllDig::new_mmio_at(CORE_PLL_BASE) };
+    let mut ip_periph_pll = unsafe { PllDig::new_mmio_at(PERIPH_PLL_BASE) };
+
+    if VERBOSE_DEBUGGING {
+        print_clock_setup("core", &mut ip_core_dfs, &mut ip_core_pll);
+        print_clock_setup("periph", &mut ip_periph_dfs, &mut ip_periph_pll);
+    }
+
+    if !pll_matches(&mut ip_core_pll, &config.core_pll)
+        || !dfs_matches(&mut ip_core_dfs, &config.core_dfs)
+    {
+        // SAFETY: Only this core is running, so nothing else is using this mux
+        let mut core_mux = unsafe { crate::mc_cgm::mux(RTU_CORE_CGM, RTU_CORE_MUX) };
+        let source = crate::mc_cgm::selected(&mut core_mux);
+        crate::mc_cgm::switch_mux(&mut core_mux, crate::mc_cgm::ClockSource::FIRC)
+            .map_err(Error::CoreClockSwitch)?;
+        configure_tree(
+            &mut ip_core_pll,
+            &mut ip_core_dfs,
+            &config.core_pll,
+            &config.core_dfs,
+        )?;
+        crate::mc_cgm::switch_mux(&mut core_mux, source).map_err(Error::CoreClockSwitch)?;
+    }
+    configure_tree(
+        &mut ip_periph_pll,
+        &mut ip_periph_dfs,
+        &config.periph_pll,
+        &config.periph_dfs,
+    )?;
+
+    if VERBOSE_DEBUGGING {
+        print_clock_setup("core", &mut ip_core_dfs, &mut ip_core_pll);
+        print_clock_setup("periph", &mut ip_periph_dfs, &mut ip_periph_pll);
+    }
+
+    Ok(Clocks {
+        core: PllClocks::read(&mut ip_core_pll, &mut ip_core_dfs),
+        periph: PllClocks::read(&mut ip_periph_pll, &mut ip_periph_dfs),
+    })
+}
+
+/// Configure one PLL and the DFS it feeds
+fn configure_tree(
+    pll: &mut MmioPllDig,
+    dfs: &mut MmioDfs,
+    pll_config: &PllConfig,
+    dfs_config: &DfsConfig,
+) -> Result<(), Error> {
+    if pll_matches(pll, pll_config) {
+        if !dfs_matches(dfs, dfs_config) {
+            configure_dfs(dfs, dfs_config)?;
+        }
+        return Ok(());
+    }
+    // The DFS must not run whilst its input is unstable
+    dfs.write_ctl(DfsCtl::new_with_raw_value(0).with_in_reset(true));
+    configure_plldig(pll, pll_config)?;
+    configure_dfs(dfs, dfs_config)
+}
+
+/// Is this PLL already locked and running with these settings?
+fn pll_matches(pll: &mut MmioPllDig, config: &PllConfig) -> bool {
+    if pll.read_pllcr().pd() || !pll.read_pllsr().locked() {
+        return false;
+    }
+    if pll.read_pllclkmux().select_fxosc() != (config.source == PllSource::Fxosc) {
+        return false;
+    }
+    let dv = pll.read_plldv();
+    if dv.rdiv() != config.rdiv || dv.mfi() != config.mfi {
+        return false;
+    }
+    let fd = pll.read_pllfd();
+    if fd.mfn() != config.mfn || fd.sdmen() != (config.mfn.value() != 0) {
+        return false;
+    }
+    for (idx, odiv) in config.odiv.iter().enumerate() {
+        let Ok(reg) = pll.read_pllodiv(idx) else {
+            return false;
+        };
+        match odiv {
+            Some(div) if reg.de() && reg.div() == *div => {}
+            None if !reg.de() => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Is this DFS already running with these settings?
+fn dfs_matches(dfs: &mut MmioDfs, config: &DfsConfig) -> bool {
+    if dfs.read_ctl().in_reset() {
+        return false;
+    }
+    let reset = dfs.read_portreset().raw_value();
+    let locked = dfs.read_portsr().raw_value();
+    for (idx, port) in config.ports.iter().enumerate() {
+        let mask = 1 << idx;
+        match port {
+            Some(port) => {
+                let Ok(reg) = dfs.read_dvports(idx) else {
+                    return false;
+                };
+                if (reset & mask) != 0
+                    || (locked & mask) == 0
+                    || reg.mfi() != port.mfi
+                    || reg.mfn() != port.mfn
+                {
+                    return false;
+                }
+            }
+            None => {
+                if (reset & mask) == 0 {
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Power down, reprogram and relock a PLL
+fn configure_plldig(pll: &mut MmioPllDig, config: &PllConfig) -> Result<(), Error> {
+    // Stop the outputs, then power down the PLL
+    for idx in 0..config.odiv.len() {
+        let _ = pll.write_pllodiv(idx, PllDigOdiv::new_with_raw_value(0));
+    }
+    pll.write_pllcr(PllDigCr::new_with_raw_value(0).with_pd(true));
+
+    // Select the input and program the loop dividers
+    pll.write_pllclkmux(
+        PllDigClkMux::new_with_raw_value(0).with_select_fxosc(config.source == PllSource::Fxosc),
+    );
+    pll.write_plldv(
+        PllDigDv::new_with_raw_value(0)
+            .with_rdiv(config.rdiv)
+            .with_mfi(config.mfi),
+    );
+    pll.write_pllfd(
+        PllDigFd::new_with_raw_value(0)
+            .with_sdmen(config.mfn.value() != 0)
+            .with_mfn(config.mfn),
+    );
+
+    // Power up and wait for lock
+    pll.write_pllcr(PllDigCr::new_with_raw_value(0).with_pd(false));
+    if !poll(|| pll.read_pllsr().locked()) {
+        return Err(Error::PllLockTimeout);
+    }
+
+    // Bring the outputs back
+    for (idx, odiv) in config.odiv.iter().enumerate() {
+        if let Some(div) = odiv {
+            let _ = pll.write_pllodiv(
+                idx,
+                PllDigOdiv::new_with_raw_value(0)
+                    .with_div(*div)
+                    .with_de(true),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Reprogram the DFS ports and wait for them to lock
+fn configure_dfs(dfs: &mut MmioDfs, config: &DfsConfig) -> Result<(), Error> {
+    // Hold every port in reset whilst we change the dividers
+    dfs.write_portreset(DfsPortReset::new_with_raw_value(0x3F));
+    let mut reset_mask = 0x3F;
+    for (idx, port) in config.ports.iter().enumerate() {
+        if let Some(port) = port {
+            let _ = dfs.write_dvports(
+                idx,
+                DfsDvPort::new_with_raw_value(0)
+                    .with_mfi(port.mfi)
+                    .with_mfn(port.mfn),
+            );
+            reset_mask &= !(1 << idx);
+        }
+    }
+
+    // Release the phase generator, then the ports we want
+    dfs.write_ctl(DfsCtl::new_with_raw_value(0).with_in_reset(false));
+    dfs.write_portreset(DfsPortReset::new_with_raw_value(reset_mask));
+
+    for (idx, port) in config.ports.iter().enumerate() {
+        if port.is_some() && !poll(|| (dfs.read_portsr().raw_value() & (1 << idx)) != 0) {
+            return Err(Error::DfsLockTimeout { port: idx });
+        }
+    }
+
+    Ok(())
+}
+
+/// Calculate the system counter frequency, in Hz
+///
+/// The counter runs from `source_hz`, through an MC_CGM divider and then the
+/// RTU's `CNTDIV` divider, each of which divides by its value plus one.
+pub const fn counter_hz(source_hz: u32, cgm_div: u8, cntdiv: u8) -> u32 {
+    source_hz / (cgm_div as u32 + 1) / (cntdiv as u32 + 1)
+}
+
+/// Measure the Generic Timer frequency, using the CPU cycle counter
+///
+/// Takes [`COUNTER_MEASURE_CYCLES`] CPU cycles (10 ms at 800 MHz). Only as
+/// accurate as `cpu_hz`.
+#[cfg(target_arch = "arm")]
+pub fn measure_counter_hz(cpu_hz: u32) -> u32 {
+    use cortex_ar::generic_timer::GenericTimer;
+
+    // Safety: we only read the counter
+    let timer = unsafe { cortex_ar::generic_timer::El1VirtualTimer::new() };
+    // Enable and reset the cycle counter
+    //
+    // SAFETY: Nothing else uses the PMU
+    unsafe {
+        core::arch::asm!(
+            "mrc p15, 0, {tmp}, c9, c12, 0", // Read PMCR
+            "orr {tmp}, {tmp}, #5",          // Set E and C
+            "mcr p15, 0, {tmp}, c9, c12, 0", // Write PMCR
+            "mov {tmp}, #0x80000000",
+            "mcr p15, 0, {tmp}, c9, c12, 1", // Write PMCNTENSET
+            "isb",
+            tmp = out(reg) _,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    let read_cycles = || -> u32 {
+        let cycles: u32;
+        // SAFETY: Reading PMCCNTR has no side-effects
+        unsafe {
+            core::arch::asm!(
+                "mrc p15, 0, {cycles}, c9, c13, 0",
+                cycles = out(reg) cycles,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        cycles
+    };
+    let start_ticks = timer.counter();
+    let start_cycles = read_cycles();
+    let mut cycles = 0;
+    while cycles < COUNTER_MEASURE_CYCLES {
+        cycles = read_cycles().wrapping_sub(start_cycles);
+    }
+    let ticks = timer.counter() - start_ticks;
+    (ticks * u64::from(cpu_hz) / u64::from(cycles)) as u32
+}
+
+/// Check the Generic Timer frequency in `CNTFRQ`
+///
+/// It must match [`COUNTER_HZ`], and if we know the CPU clock, it must match
+/// [`measure_counter_hz`] to within [`COUNTER_TOLERANCE_PPT`]. Returns the
+/// frequency if everything agrees.
+#[cfg(target_arch = "arm")]
+pub fn check_counter_hz(clocks: &Clocks) -> Result<u32, Error> {
+    let programmed = cortex_ar::register::Cntfrq::read().0;
+    let expected = COUNTER_HZ;
+    let measured = clocks.rtu_core_hz().map(measure_counter_hz);
+    let close_enough = |measured: u32| {
+        u64::from(measured.abs_diff(programmed)) * 1000
+            <= u64::from(programmed) * COUNTER_TOLERANCE_PPT
+    };
+    if programmed != expected || !measured.is_none_or(close_enough) {
+        return Err(Error::CounterMismatch {
+            programmed,
+            expected,
+            measured,
+        });
+    }
+    Ok(programmed)
+}
+
+/// Spin until `f` returns true, or we run out of patience
+///
+/// Returns `true` if `f` returned true.
+pub(crate) fn poll<F>(mut f: F) -> bool
+where
+    F: FnMut() -> bool,
+{
+    for _ in 0..POLL_LIMIT {
+        if f() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    false
 }
 
 fn print_clock_setup(name: &str, dfs: &mut MmioDfs, pll: &mut MmioPllDig) {
-    // The clocks seem to be set up for us
     println!("Examining {} DFS and PLL...", name);
     println!("  {:#?}", dfs.read_ctl());
     println!("  {:#?}", dfs.read_portsr());
@@ -240,4 +1164,220 @@ fn print_clock_setup(name: &str, dfs: &mut MmioDfs, pll: &mut MmioPllDig) {
     println!("  {:#?}", pll.read_pllsr());
     println!("  {:#?}", pll.read_plldv());
     println!("  {:#?}", pll.read_pllfd());
+    println!("  {:#?}", pll.read_pllclkmux());
+    for i in 0.. {
+        if let Ok(p) = pll.read_pllodiv(i) {
+            println!("  - Odiv{}: {:#?}", i, p);
+        } else {
+            break;
+        }
+    }
+    println!("  {:#?}", PllClocks::read(pll, dfs));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_divides_by_value_plus_one() {
+        assert_eq!(counter_hz(40_000_000, 0, 4), 8_000_000);
+        assert_eq!(counter_hz(40_000_000, 1, 4), 4_000_000);
+        assert_eq!(counter_hz(40_000_000, 0, 0), 40_000_000);
+        // The crystal and reset dividers give the 8 MHz we measure on the EVB
+        assert_eq!(COUNTER_HZ, 8_000_000);
+    }
+
+    #[test]
+    fn vco_integer_multiplier() {
+        // The default CORE_PLL settings: 40 MHz / 1 * 50
+        assert_eq!(
+            vco_hz(40_000_000, u3::new(1), 50, u15::new(0)),
+            2_000_000_000
+        );
+        // An rdiv of zero also divides by one
+        assert_eq!(
+            vco_hz(40_000_000, u3::new(0), 50, u15::new(0)),
+            2_000_000_000
+        );
+        // FIRC, divided by 2
+        assert_eq!(
+            vco_hz(48_000_000, u3::new(2), 60, u15::new(0)),
+            1_440_000_000
+        );
+    }
+
+    #[test]
+    fn vco_fractional_multiplier() {
+        // 40 MHz * (50 + 9216 / 18432) = 40 MHz * 50.5
+        assert_eq!(
+            vco_hz(40_000_000, u3::new(1), 50, u15::new(9216)),
+            2_020_000_000
+        );
+        // The smallest fractional step rounds down
+        assert_eq!(
+            vco_hz(40_000_000, u3::new(1), 50, u15::new(1)),
+            2_000_002_170
+        );
+    }
+
+    #[test]
+    fn pll_output_divides_by_div_plus_one() {
+        assert_eq!(pll_output_hz(2_000_000_000, 0), 2_000_000_000);
+        assert_eq!(pll_output_hz(2_000_000_000, 1), 1_000_000_000);
+        assert_eq!(pll_output_hz(2_000_000_000, 4), 400_000_000);
+    }
+
+    #[test]
+    fn dfs_port_frequencies() {
+        // The default CORE_DFS port 0: 2 GHz / (2 * (1 + 9 / 36)) = 800 MHz
+        assert_eq!(dfs_port_hz(2_000_000_000, 1, u6::new(9)), 800_000_000);
+        // Integer divider: 2 GHz / (2 * 2)
+        assert_eq!(dfs_port_hz(2_000_000_000, 2, u6::new(0)), 500_000_000);
+        // Fractional divider: 2 GHz / (2 * (2 + 18 / 36)) = 400 MHz
+        assert_eq!(dfs_port_hz(2_000_000_000, 2, u6::new(18)), 400_000_000);
+    }
+
+    #[test]
+    fn dfs_zero_divider_gives_zero() {
+        assert_eq!(dfs_port_hz(2_000_000_000, 0, u6::new(0)), 0);
+    }
+
+    /// Check a solved configuration is legal, and gives what was asked for
+    fn check_solution(request: &PllRequest, pll: &PllConfig, dfs: &DfsConfig) {
+        let clocks = PllClocks::from_config(pll, dfs);
+        assert!((VCO_MIN_HZ..=VCO_MAX_HZ).contains(&clocks.vco_hz));
+        let rdiv = u64::from(pll.rdiv.value().max(1));
+        let pfd_hz = u64::from(request.source.frequency_hz()) / rdiv;
+        assert!((PFD_MIN_HZ..=PFD_MAX_HZ).contains(&pfd_hz));
+        assert!((PLL_MFI_MIN..=PLL_MFI_MAX).contains(&u64::from(pll.mfi)));
+        assert_eq!(pll.source, request.source);
+        assert_eq!(clocks.outputs, request.outputs);
+        assert_eq!(clocks.dfs_ports, request.dfs_ports);
+    }
+
+    /// A request for just one DFS port
+    const fn dfs_request(source: PllSource, hz: u32) -> PllRequest {
+        PllRequest {
+            source,
+            outputs: [None; 6],
+            dfs_ports: [Some(hz), None, None, None, None, None],
+        }
+    }
+
+    #[test]
+    fn solve_default_core_clock() {
+        let request = dfs_request(PllSource::Fxosc, 800_000_000);
+        let (pll, dfs) = request.solve().unwrap();
+        check_solution(&request, &pll, &dfs);
+        // Integer mode is preferred
+        assert_eq!(pll.mfn.value(), 0);
+    }
+
+    #[test]
+    fn solve_default_config() {
+        let core = PllRequest {
+            source: PllSource::Fxosc,
+            outputs: [
+                Some(1_000_000_000),
+                Some(1_000_000_000),
+                None,
+                None,
+                None,
+                None,
+            ],
+            dfs_ports: [Some(800_000_000), Some(400_000_000), None, None, None, None],
+        };
+        let periph = PllRequest {
+            source: PllSource::Fxosc,
+            outputs: [
+                Some(100_000_000),
+                Some(500_000_000),
+                Some(400_000_000),
+                Some(200_000_000),
+                None,
+                None,
+            ],
+            dfs_ports: [Some(400_000_000), None, None, None, None, None],
+        };
+        let config = ClockRequest { core, periph }.solve().unwrap();
+        check_solution(&core, &config.core_pll, &config.core_dfs);
+        check_solution(&periph, &config.periph_pll, &config.periph_dfs);
+        assert_eq!(config.clocks(), ClockConfig::DEFAULT.clocks());
+    }
+
+    #[test]
+    fn solve_from_firc() {
+        let request = PllRequest {
+            source: PllSource::Firc,
+            outputs: [Some(480_000_000), None, None, None, None, None],
+            dfs_ports: [None, Some(600_000_000), None, None, None, None],
+        };
+        let (pll, dfs) = request.solve().unwrap();
+        check_solution(&request, &pll, &dfs);
+    }
+
+    #[test]
+    fn solve_needs_fractional_mode() {
+        // 40 MHz * (50 + 36 / 18432) - only reachable with a fractional
+        // multiplier
+        let request = PllRequest {
+            source: PllSource::Fxosc,
+            outputs: [Some(2_000_078_125), None, None, None, None, None],
+            dfs_ports: [None; 6],
+        };
+        let (pll, dfs) = request.solve().unwrap();
+        check_solution(&request, &pll, &dfs);
+        assert_ne!(pll.mfn.value(), 0);
+    }
+
+    #[test]
+    fn solve_rejects_empty_request() {
+        let request = PllRequest {
+            source: PllSource::Fxosc,
+            outputs: [None; 6],
+            dfs_ports: [None; 6],
+        };
+        assert_eq!(request.solve(), Err(Error::NothingRequested));
+    }
+
+    #[test]
+    fn solve_rejects_impossible_requests() {
+        // Slower than the slowest VCO divided by the largest output divider
+        let request = PllRequest {
+            source: PllSource::Fxosc,
+            outputs: [Some(1_000_000), None, None, None, None, None],
+            dfs_ports: [None; 6],
+        };
+        assert_eq!(request.solve(), Err(Error::NoSolution));
+        // Zero Hz
+        assert_eq!(
+            dfs_request(PllSource::Fxosc, 0).solve(),
+            Err(Error::NoSolution)
+        );
+        // Two outputs which no single VCO frequency can give
+        let request = PllRequest {
+            source: PllSource::Fxosc,
+            outputs: [
+                Some(1_000_000_000),
+                Some(999_999_999),
+                None,
+                None,
+                None,
+                None,
+            ],
+            dfs_ports: [None; 6],
+        };
+        assert_eq!(request.solve(), Err(Error::NoSolution));
+    }
+
+    #[test]
+    fn from_request_works_in_const() {
+        const CONFIG: ClockConfig = ClockConfig::from_request(&ClockRequest {
+            core: dfs_request(PllSource::Fxosc, 800_000_000),
+            periph: dfs_request(PllSource::Fxosc, 400_000_000),
+        });
+        assert_eq!(CONFIG.clocks().core.dfs_ports[0], Some(800_000_000));
+        assert_eq!(CONFIG.clocks().periph.dfs_ports[0], Some(400_000_000));
+    }
 }
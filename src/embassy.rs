@@ -0,0 +1,193 @@
+//! Embassy support for the S32Z2
+//!
+//! Provides an `embassy-time` driver, built on the alarm queue in
+//! [`crate::time`], and an [`Executor`] which sleeps in `wfi` when it has
+//! nothing to do.
+//!
+//! Embassy must only be used on one core - the one which called [`init`].
+//!
+//! ```rust,ignore
+//! static mut EXECUTOR: Option<Executor> = None;
+//!
+//! let mut gic = unsafe { Gic::init() };
+//! s32z2_rust_demo::embassy::init(&mut gic, 0x31).unwrap();
+//! let executor = unsafe { (*core::ptr::addr_of_mut!(EXECUTOR)).insert(Executor::new()) };
+//! executor.run(|spawner| {
+//!     spawner.must_spawn(my_task());
+//! });
+//! ```
+//!
+//! The driver needs one of this core's [`time`] alarm slots. If they are all
+//! in use, it wakes every waiting task straight away so their timers try
+//! again, rather than missing a deadline - see [`alarm_failures`].
+
+use core::{
+    cell::RefCell,
+    marker::PhantomData,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    task::Waker,
+};
+
+use critical_section::Mutex;
+use embassy_executor::{raw, Spawner};
+use embassy_time_driver::{Driver, TICK_HZ};
+use embassy_time_queue_utils::Queue;
+
+use crate::{
+    gic::Gic,
+    ticks,
+    time::{self, AlarmId, Instant},
+};
+
+/// The state of the time driver
+struct State {
+    /// Wakers, sorted by deadline (in Embassy ticks)
+    queue: Queue,
+    /// The alarm for the earliest deadline, if any
+    alarm: Option<AlarmId>,
+}
+
+/// Our Embassy time driver
+struct TimeDriver {
+    state: Mutex<RefCell<State>>,
+}
+
+embassy_time_driver::time_driver_impl!(static DRIVER: TimeDriver = TimeDriver {
+    state: Mutex::new(RefCell::new(State {
+        queue: Queue::new(),
+        alarm: None,
+    })),
+});
+
+impl Driver for TimeDriver {
+    fn now(&self) -> u64 {
+        counter_to_embassy(Instant::now().ticks())
+    }
+
+    fn schedule_wake(&self, at: u64, waker: &Waker) {
+        critical_section::with(|cs| {
+            let mut state = self.state.borrow_ref_mut(cs);
+            if state.queue.schedule_wake(at, waker) {
+                rearm(&mut state);
+            }
+        })
+    }
+}
+
+/// Wake anything that has expired, and set an alarm for the next deadline
+fn rearm(state: &mut State) {
+    if let Some(alarm) = state.alarm.take() {
+        time::cancel_alarm(alarm);
+    }
+    let next = state.queue.next_expiration(DRIVER.now());
+    if next == u64::MAX {
+        return;
+    }
+    let deadline = Instant::from_ticks(embassy_to_counter(next));
+    match time::set_alarm(deadline, on_alarm) {
+        Ok(alarm) => state.alarm = Some(alarm),
+        Err(_) => {
+            // Every alarm slot is in use. Wake everything, so each timer is
+            // polled again and asks for another alarm.
+            ALARM_FAILURES.fetch_add(1, Ordering::Relaxed);
+            state.queue.next_expiration(u64::MAX);
+        }
+    }
+}
+
+/// How many times the driver could not get an alarm slot
+static ALARM_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// How many times the driver could not get an alarm slot
+///
+/// Each failure means the executor woke its timers early, and polled them
+/// again, instead of sleeping.
+pub fn alarm_failures() -> u32 {
+    ALARM_FAILURES.load(Ordering::Relaxed)
+}
+
+/// Called from the timer interrupt when the earliest deadline passes
+fn on_alarm(_id: AlarmId) {
+    critical_section::with(|cs| {
+        let mut state = DRIVER.state.borrow_ref_mut(cs);
+        state.alarm = None;
+        rearm(&mut state);
+    })
+}
+
+/// Convert a system counter value into Embassy ticks
+fn counter_to_embassy(ticks: u64) -> u64 {
+    ticks::convert_floor(ticks, u64::from(time::frequency_hz()), TICK_HZ)
+}
+
+/// Convert Embassy ticks into a system counter value, rounding up
+fn embassy_to_counter(ticks: u64) -> u64 {
+    ticks::convert_ceil(ticks, TICK_HZ, u64::from(time::frequency_hz()))
+}
+
+/// Set up the timer interrupt for Embassy on this core
+///
+/// This is [`time::init`] - don't call both.
+pub fn init(gic: &mut Gic, priority: u8) -> Result<(), time::Error> {
+    time::init(gic, priority)
+}
+
+/// Set when the executor has work to do
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+/// Called by Embassy when a task is woken
+#[export_name = "__pender"]
+fn __pender(_context: *mut ()) {
+    SIGNALLED.store(true, Ordering::Release);
+}
+
+/// An Embassy executor which sleeps until an interrupt wakes a task
+pub struct Executor {
+    inner: raw::Executor,
+    not_send: PhantomData<*mut ()>,
+}
+
+impl Executor {
+    /// Create a new executor
+    pub fn new() -> Executor {
+        Executor {
+            inner: raw::Executor::new(core::ptr::null_mut()),
+            not_send: PhantomData,
+        }
+    }
+
+    /// Run the executor, forever
+    ///
+    /// `init` is given a [`Spawner`] so it can spawn the first tasks. IRQs are
+    /// enabled before polling starts.
+    pub fn run(&'static mut self, init: impl FnOnce(Spawner)) -> ! {
+        init(self.inner.spawner());
+        // Safety: we are not in a critical section
+        unsafe {
+            cortex_ar::interrupt::enable();
+        }
+        loop {
+            SIGNALLED.store(false, Ordering::Release);
+            // Safety: we only poll from this thread
+            unsafe {
+                self.inner.poll();
+            }
+            // Check with IRQs masked, so a wake-up can't slip in before the
+            // `wfi`. A pending IRQ still wakes us.
+            cortex_ar::interrupt::disable();
+            if !SIGNALLED.load(Ordering::Acquire) {
+                cortex_ar::asm::wfi();
+            }
+            // Safety: as above
+            unsafe {
+                cortex_ar::interrupt::enable();
+            }
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Executor {
+        Executor::new()
+    }
+}
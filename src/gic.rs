@@ -0,0 +1,630 @@
+//! GICv3 set-up code for the S32Z2
+//!
+//! Finds the GIC for this cluster using PERIPHBASE, initialises the
+//! Distributor (on core 0) and this core's Redistributor, and lets you
+//! configure each interrupt in one go. Anything that takes an interrupt will
+//! take either an [`IntId`] or an [`Interrupt`](crate::interrupt::Interrupt).
+//!
+//! It also has a table-driven interrupt dispatcher. Register a handler for
+//! each interrupt with [`register_handler`], and call [`dispatch`] from your
+//! `#[irq]` handler:
+//!
+//! ```rust,ignore
+//! #[cortex_r_rt::irq]
+//! fn irq_handler() {
+//!     s32z2_rust_demo::gic::dispatch(InterruptGroup::Group1);
+//! }
+//! ```
+//!
+//! Interrupts in Group 0 are signalled as FIQs rather than IRQs, and are
+//! serviced by `_fiq_handler`. By default that calls [`dispatch`] for Group 0,
+//! using the same handler table, but an application can provide its own
+//! `#[no_mangle] extern "C" fn _fiq_handler()`. Use Group 0 (see
+//! [`InterruptConfig::fiq`]) for things like FCCU alarms which must not wait
+//! behind ordinary IRQ work. Call [`enable_fiq`] to unmask FIQs on this core.
+//!
+//! With the `nested-irq` feature, the IRQ entry code saves the interrupted
+//! state on the System mode stack and runs your `#[irq]` handler in System
+//! mode. [`dispatch`] then re-enables IRQs while each handler runs, so
+//! anything with a higher priority than the running priority can pre-empt it.
+//!
+//! With the `el0-tasks` feature, an IRQ which interrupts an EL0 task runs your
+//! `#[irq]` handler in IRQ mode, on the IRQ stack, as the System mode stack
+//! pointer is the task's.
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use arm_gic::{
+    gicv3::{GicCpuInterface, GicV3, Group, InterruptGroup, SecureIntGroup},
+    IntId, Trigger, UniqueMmioPointer,
+};
+
+/// Offset from PERIPHBASE for GIC Distributor
+pub const GICD_BASE_OFFSET: usize = 0x0000_0000usize;
+
+/// Offset from PERIPHBASE for the first GIC Redistributor
+pub const GICR_BASE_OFFSET: usize = 0x0010_0000usize;
+
+/// Offset from the GIC Distributor base for the Interrupt Controller Type
+/// Register (`GICD_TYPER`)
+const GICD_TYPER_OFFSET: usize = 0x0004;
+
+/// Offset from the GIC Distributor base for the first Interrupt Routing
+/// Register (`GICD_IROUTER<n>`, for INTID `n`)
+const GICD_IROUTER_OFFSET: usize = 0x6000;
+
+/// How many cores (and so Redistributors) there are in the cluster
+pub const NUM_CORES: usize = 4;
+
+/// How many SGIs and PPIs each core has
+const NUM_PRIVATE: usize = 32;
+
+/// How many SPIs the dispatcher has room for (INTID 32 to 1019)
+pub const NUM_SPIS: usize = 988;
+
+/// The priority mask set on each core by [`Gic::init`]
+///
+/// Interrupts need a priority value lower than this to be signalled.
+pub const DEFAULT_PRIORITY_MASK: u8 = 0x80;
+
+/// Errors that can occur when configuring the GIC
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The GIC driver rejected the request (e.g. the INTID is out of range)
+    Gic,
+    /// That core does not exist
+    NoSuchCore,
+    /// SGIs and PPIs cannot be routed to another core, and SPIs cannot be
+    /// configured on another core's Redistributor
+    BadTarget,
+    /// That INTID cannot have a handler (e.g. it is a special INTID)
+    NoSuchInterrupt,
+    /// A handler is already registered for that interrupt
+    AlreadyRegistered,
+}
+
+/// Which core an interrupt is for
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// The core doing the configuration
+    ThisCore,
+    /// A specific core in the cluster (0 to 3)
+    ///
+    /// For SGIs and PPIs, this picks which core's copy of the interrupt to
+    /// configure. For SPIs, this is where the interrupt is routed.
+    Core(usize),
+}
+
+/// How an interrupt should be configured
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InterruptConfig {
+    /// Priority - lower numbers are more urgent
+    pub priority: u8,
+    /// Interrupt Group
+    pub group: Group,
+    /// Edge or Level triggered
+    pub trigger: Trigger,
+    /// Which core the interrupt is for
+    pub target: Target,
+}
+
+impl InterruptConfig {
+    /// A level-triggered, Group 1, interrupt for this core, with the given
+    /// priority
+    pub const fn new(priority: u8) -> InterruptConfig {
+        InterruptConfig {
+            priority,
+            group: Group::Group1NS,
+            trigger: Trigger::Level,
+            target: Target::ThisCore,
+        }
+    }
+
+    /// A level-triggered, Group 0, interrupt for this core, with the given
+    /// priority
+    ///
+    /// Group 0 interrupts arrive as FIQs.
+    pub const fn fiq(priority: u8) -> InterruptConfig {
+        InterruptConfig {
+            priority,
+            group: Group::Secure(SecureIntGroup::Group0),
+            trigger: Trigger::Level,
+            target: Target::ThisCore,
+        }
+    }
+}
+
+/// The interrupt controller, as seen from one core
+pub struct Gic {
+    gic: GicV3<'static>,
+    gicd_base: *mut u8,
+    core: usize,
+}
+
+impl Gic {
+    /// Initialise the GIC for this core
+    ///
+    /// On core 0 this also initialises the Distributor, so core 0 must call
+    /// this before any other core does. Sets the priority mask to
+    /// [`DEFAULT_PRIORITY_MASK`].
+    ///
+    /// # Safety
+    ///
+    /// Only call this once per core.
+    pub unsafe fn init() -> Gic {
+        let core = crate::core_id();
+        // Get the GIC address by reading CBAR
+        let periphbase = cortex_ar::register::ImpCbar::read().periphbase();
+        let gicd_base = periphbase.wrapping_byte_add(GICD_BASE_OFFSET);
+        let gicr_base = periphbase.wrapping_byte_add(GICR_BASE_OFFSET);
+        let gicd = unsafe { UniqueMmioPointer::new(NonNull::new(gicd_base.cast()).unwrap()) };
+        let gicr_base = NonNull::new(gicr_base.cast()).unwrap();
+        let mut gic: GicV3 = unsafe { GicV3::new(gicd, gicr_base, NUM_CORES, false) };
+        if core == 0 {
+            gic.setup(core);
+        } else {
+            gic.init_cpu(core);
+        }
+        GicCpuInterface::set_priority_mask(DEFAULT_PRIORITY_MASK);
+        GicCpuInterface::enable_group0(true);
+        Gic {
+            gic,
+            gicd_base: gicd_base.cast(),
+            core,
+        }
+    }
+
+    /// Which core this handle was created on
+    pub fn core(&self) -> usize {
+        self.core
+    }
+
+    /// Configure an interrupt, and enable it
+    pub fn configure(
+        &mut self,
+        int_id: impl Into<IntId>,
+        config: &InterruptConfig,
+    ) -> Result<(), Error> {
+        let int_id = int_id.into();
+        let target_core = match config.target {
+            Target::ThisCore => self.core,
+            Target::Core(core) if core < NUM_CORES => core,
+            Target::Core(_) => return Err(Error::NoSuchCore),
+        };
+        let redistributor = if int_id.is_private() {
+            Some(target_core)
+        } else {
+            self.route(int_id, target_core)?;
+            None
+        };
+        self.gic
+            .set_interrupt_priority(int_id, redistributor, config.priority)
+            .map_err(|_| Error::Gic)?;
+        self.gic
+            .set_group(int_id, redistributor, config.group)
+            .map_err(|_| Error::Gic)?;
+        self.gic
+            .set_trigger(int_id, redistributor, config.trigger)
+            .map_err(|_| Error::Gic)?;
+        self.gic
+            .enable_interrupt(int_id, redistributor, true)
+            .map_err(|_| Error::Gic)?;
+        Ok(())
+    }
+
+    /// Enable or disable an interrupt for this core
+    ///
+    /// SPIs are enabled for whichever core they are routed to.
+    pub fn enable(&mut self, int_id: impl Into<IntId>, enable: bool) -> Result<(), Error> {
+        let int_id = int_id.into();
+        let redistributor = int_id.is_private().then_some(self.core);
+        self.gic
+            .enable_interrupt(int_id, redistributor, enable)
+            .map_err(|_| Error::Gic)
+    }
+
+    /// One more than the largest SPI INTID this Distributor implements
+    ///
+    /// Worked out from `GICD_TYPER.ITLinesNumber`, and never more than 1020
+    /// because INTIDs 1020 to 1023 are special.
+    fn spi_limit(&self) -> usize {
+        let typer = self
+            .gicd_base
+            .wrapping_byte_add(GICD_TYPER_OFFSET)
+            .cast::<u32>();
+        // Safety: GICD_TYPER is a read-only register in the Distributor we
+        // were created with
+        let it_lines_number = unsafe { typer.read_volatile() } & 0x1F;
+        (32 * (it_lines_number as usize + 1)).min(1020)
+    }
+
+    /// Route an SPI to a core in this cluster
+    ///
+    /// Refuses any INTID that isn't an SPI this Distributor implements, as
+    /// there would be no `GICD_IROUTER<n>` register to write.
+    fn route(&mut self, int_id: IntId, core: usize) -> Result<(), Error> {
+        let intid = u32::from(int_id) as usize;
+        if !(NUM_PRIVATE..self.spi_limit()).contains(&intid) {
+            return Err(Error::NoSuchInterrupt);
+        }
+        let irouter = self
+            .gicd_base
+            .wrapping_byte_add(GICD_IROUTER_OFFSET + (intid * 8))
+            .cast::<u32>();
+        // Aff0 is the core, all other affinity levels are zero, and
+        // Interrupt Routing Mode is 'this core only'.
+        unsafe {
+            irouter.write_volatile(core as u32);
+            irouter.wrapping_add(1).write_volatile(0);
+        }
+        Ok(())
+    }
+
+    /// Get the underlying `arm-gic` driver, for anything not wrapped here
+    pub fn inner(&mut self) -> &mut GicV3<'static> {
+        &mut self.gic
+    }
+}
+
+/// A function that services one interrupt
+///
+/// It is given the INTID that fired, so one function can service several
+/// interrupts. It does not need to end the interrupt - [`dispatch`] does that.
+pub type Handler = fn(IntId);
+
+/// Handlers for the SGIs and PPIs of each core
+///
+/// Lives in `.shared` (the `RTU0_SHARED_RAM` region from `build.rs`) because
+/// one image runs on every core, and a static in `.bss` would be in core 0's
+/// data RAM. `.shared` is non-cacheable, where exclusive accesses may not be
+/// supported, so the tables and counters only see plain loads and stores.
+/// Handlers are registered under [`crate::sema42::HANDLER_GATE`], and each
+/// core only writes its own counters, with IRQs and FIQs masked.
+#[link_section = ".shared"]
+static PRIVATE_HANDLERS: [[AtomicUsize; NUM_PRIVATE]; NUM_CORES] =
+    [const { [const { AtomicUsize::new(0) }; NUM_PRIVATE] }; NUM_CORES];
+
+/// Handlers for SPIs, shared by every core
+#[link_section = ".shared"]
+static SPI_HANDLERS: [AtomicUsize; NUM_SPIS] = [const { AtomicUsize::new(0) }; NUM_SPIS];
+
+/// Spurious interrupts seen by each core
+#[link_section = ".shared"]
+static SPURIOUS: [AtomicU32; NUM_CORES] = [const { AtomicU32::new(0) }; NUM_CORES];
+
+/// Interrupts with no handler seen by each core
+#[link_section = ".shared"]
+static UNHANDLED: [AtomicU32; NUM_CORES] = [const { AtomicU32::new(0) }; NUM_CORES];
+
+/// Find the handler table entry for an interrupt
+///
+/// SGIs and PPIs use the table for the given core.
+fn handler_slot(int_id: IntId, core: usize) -> Result<&'static AtomicUsize, Error> {
+    let intid = u32::from(int_id) as usize;
+    if intid < NUM_PRIVATE {
+        PRIVATE_HANDLERS
+            .get(core)
+            .map(|table| &table[intid])
+            .ok_or(Error::NoSuchCore)
+    } else {
+        SPI_HANDLERS
+            .get(intid - NUM_PRIVATE)
+            .ok_or(Error::NoSuchInterrupt)
+    }
+}
+
+/// Register a handler for an interrupt
+///
+/// For SGIs and PPIs, this registers the handler on the current core only.
+/// SPI handlers are used by whichever core the SPI is routed to.
+///
+/// Do this before enabling the interrupt.
+pub fn register_handler(int_id: impl Into<IntId>, handler: Handler) -> Result<(), Error> {
+    let int_id = int_id.into();
+    let slot = handler_slot(int_id, crate::core_id())?;
+    crate::sema42::with_gate(crate::sema42::HANDLER_GATE, || {
+        if slot.load(Ordering::Acquire) != 0 {
+            return Err(Error::AlreadyRegistered);
+        }
+        slot.store(handler as usize, Ordering::Release);
+        Ok(())
+    })
+}
+
+/// Remove the handler for an interrupt
+///
+/// As with [`register_handler`], SGIs and PPIs are per-core.
+pub fn unregister_handler(int_id: impl Into<IntId>) -> Result<(), Error> {
+    let int_id = int_id.into();
+    let slot = handler_slot(int_id, crate::core_id())?;
+    crate::sema42::with_gate(crate::sema42::HANDLER_GATE, || {
+        slot.store(0, Ordering::Release);
+    });
+    Ok(())
+}
+
+/// Service every pending interrupt in the given group
+///
+/// Acknowledges each interrupt, calls its registered handler, and then ends
+/// the interrupt. Interrupts with no handler are counted, and ended.
+pub fn dispatch(group: InterruptGroup) {
+    let core = crate::core_id();
+    let mut serviced = false;
+    while let Some(int_id) = GicCpuInterface::get_and_acknowledge_interrupt(group) {
+        serviced = true;
+        match handler_slot(int_id, core).map(|slot| slot.load(Ordering::Acquire)) {
+            Ok(raw) if raw != 0 => {
+                // Safety: we only ever store `Handler` function pointers in
+                // the tables
+                let handler: Handler = unsafe { core::mem::transmute::<usize, Handler>(raw) };
+                call_handler(handler, int_id);
+            }
+            _ => {
+                count(&UNHANDLED[core]);
+            }
+        }
+        GicCpuInterface::end_interrupt(int_id, group);
+    }
+    if !serviced {
+        count(&SPURIOUS[core]);
+    }
+}
+
+/// Add one to one of this core's counters
+///
+/// Masks IRQs and FIQs, so an FIQ can't land between the load and the store.
+fn count(counter: &AtomicU32) {
+    without_interrupts(|| {
+        counter.store(
+            counter.load(Ordering::Relaxed).wrapping_add(1),
+            Ordering::Relaxed,
+        );
+    });
+}
+
+/// Call an interrupt handler, with IRQs masked
+#[cfg(not(feature = "nested-irq"))]
+fn call_handler(handler: Handler, int_id: IntId) {
+    handler(int_id);
+}
+
+/// Call an interrupt handler, letting higher priority IRQs pre-empt it
+///
+/// The GIC only signals interrupts with a higher priority than the running
+/// priority, so we only need to unmask IRQs. If we are already running at the
+/// highest priority nothing could pre-empt us, so we don't bother.
+#[cfg(feature = "nested-irq")]
+fn call_handler(handler: Handler, int_id: IntId) {
+    if running_priority() == 0 {
+        handler(int_id);
+        return;
+    }
+    // Safety: the nested IRQ entry code has saved LR_irq and SPSR_irq and
+    // moved us to System mode, so another IRQ will not corrupt them
+    unsafe {
+        cortex_ar::interrupt::enable();
+    }
+    handler(int_id);
+    cortex_ar::interrupt::disable();
+}
+
+/// Read the priority of the interrupt this core is currently servicing
+///
+/// Reads `ICC_RPR`, which is `0xFF` when the core is idle.
+pub fn running_priority() -> u8 {
+    let rpr: u32;
+    // Safety: reading ICC_RPR has no side-effects
+    unsafe {
+        core::arch::asm!(
+            "mrc p15, 0, {rpr}, c12, c11, 3",
+            rpr = out(reg) rpr,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    rpr as u8
+}
+
+/// Unmask FIQs on this core
+///
+/// FIQ handlers share the handler table and counters with IRQ handlers, but
+/// [`dispatch`] only updates the counters with IRQs and FIQs masked.
+/// With the `critical-section-multi-core` feature, a critical section masks
+/// FIQs as well as IRQs, so FIQ handlers can use `critical_section` like
+/// anything else. The `critical-section-single-core` implementation only
+/// masks IRQs.
+///
+/// ```rust,ignore
+/// // FCCU_ALARM_SPI comes from the RTU interrupt map
+/// let fccu_alarm = IntId::spi(FCCU_ALARM_SPI);
+/// gic::register_handler(fccu_alarm, handle_fccu_alarm)?;
+/// gic.configure(fccu_alarm, &InterruptConfig::fiq(0x10))?;
+/// unsafe { gic::enable_fiq() };
+/// ```
+///
+/// # Safety
+///
+/// Do not call this in a critical section, as it would let FIQs in. With
+/// `critical-section-single-core`, FIQ handlers must not touch anything that
+/// relies on `critical_section`, because an FIQ can arrive in the middle of a
+/// critical section.
+pub unsafe fn enable_fiq() {
+    // Safety: as above
+    unsafe {
+        core::arch::asm!("cpsie f", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Mask FIQs on this core
+pub fn disable_fiq() {
+    // Safety: masking FIQs cannot break any invariants
+    unsafe {
+        core::arch::asm!("cpsid f", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Run `f` with IRQs and FIQs masked on this core
+///
+/// Puts both masks back as they were afterwards.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let cpsr = cortex_ar::register::Cpsr::read();
+    disable_fiq();
+    cortex_ar::interrupt::disable();
+    let result = f();
+    if !cpsr.f() {
+        // Safety: FIQs were unmasked when we were called
+        unsafe { enable_fiq() };
+    }
+    if !cpsr.i() {
+        // Safety: IRQs were unmasked when we were called
+        unsafe { cortex_ar::interrupt::enable() };
+    }
+    result
+}
+
+/// The default FIQ handler, which services every pending Group 0 interrupt
+#[no_mangle]
+extern "C" fn _s32z2_default_fiq_handler() {
+    dispatch(InterruptGroup::Group0);
+}
+
+/// How many times this core was interrupted with nothing to service
+pub fn spurious_count() -> u32 {
+    SPURIOUS[crate::core_id()].load(Ordering::Relaxed)
+}
+
+/// How many interrupts this core has ended with no handler registered
+pub fn unhandled_count() -> u32 {
+    UNHANDLED[crate::core_id()].load(Ordering::Relaxed)
+}
+
+// FIQ entry code.
+//
+// Replaces the `_asm_default_fiq_handler` from cortex-r-rt, which does not
+// call any Rust code. FIQ handlers run in FIQ mode with IRQs and FIQs masked,
+// so an FIQ is never delayed by an IRQ handler and never nests.
+core::arch::global_asm!(
+    r#"
+    .section .text._asm_fiq_handler
+    .global _asm_fiq_handler
+    .type _asm_fiq_handler, %function
+    .arm
+_asm_fiq_handler:
+    // Return to the interrupted instruction
+    sub     lr, lr, #4
+    // Save the caller-saved registers on the FIQ stack
+    push    {{ r0-r3, r12, lr }}
+    vpush   {{ s0-s15 }}
+    vmrs    r0, fpscr
+    // Align the stack to 8 bytes, remembering how much we moved it
+    and     r1, sp, #4
+    sub     sp, sp, r1
+    push    {{ r0, r1 }}
+    bl      _fiq_handler
+    pop     {{ r0, r1 }}
+    add     sp, sp, r1
+    vmsr    fpscr, r0
+    vpop    {{ s0-s15 }}
+    pop     {{ r0-r3, r12, lr }}
+    // Return, restoring CPSR from SPSR_fiq
+    movs    pc, lr
+    .size _asm_fiq_handler, . - _asm_fiq_handler
+"#
+);
+
+// IRQ entry code for nested interrupts.
+//
+// Replaces the `_asm_default_irq_handler` from cortex-r-rt. We push LR_irq
+// and SPSR_irq to the System mode stack, then switch to System mode before
+// calling the `#[irq]` handler. IRQs stay masked until `dispatch` has
+// acknowledged an interrupt, and another IRQ then only clobbers LR_irq and
+// SPSR_irq, which we no longer need.
+//
+// We save the caller-saved integer and FPU registers, as the handler is an
+// AAPCS function and the interrupted code won't be expecting any changes.
+#[cfg(feature = "nested-irq")]
+core::arch::global_asm!(
+    r#"
+    .section .text._asm_irq_handler
+    .global _asm_irq_handler
+    .type _asm_irq_handler, %function
+    .arm
+_asm_irq_handler:
+    // Return to the interrupted instruction
+    sub     lr, lr, #4
+    // Push LR_irq and SPSR_irq to the System mode stack, and go there
+    srsfd   sp!, #0x1F
+    cps     #0x1F
+    // Save the caller-saved registers
+    push    {{ r0-r3, r12 }}
+    vpush   {{ s0-s15 }}
+    vmrs    r0, fpscr
+    // Align the stack to 8 bytes, remembering how much we moved it
+    and     r1, sp, #4
+    sub     sp, sp, r1
+    push    {{ r0, r1, r2, lr }}
+    bl      _irq_handler
+    pop     {{ r0, r1, r2, lr }}
+    add     sp, sp, r1
+    vmsr    fpscr, r0
+    vpop    {{ s0-s15 }}
+    pop     {{ r0-r3, r12 }}
+    // Restore the interrupted PC and CPSR (including the mode and I bit)
+    rfefd   sp!
+    .size _asm_irq_handler, . - _asm_irq_handler
+"#
+);
+
+// IRQ entry code for EL0 tasks.
+//
+// Replaces the `_asm_default_irq_handler` from cortex-r-rt, which always
+// saves the interrupted state on the System mode stack. System mode shares
+// its SP with User mode, so if we interrupted an EL0 task that would be the
+// task's stack, which the task can see and which may not have room. In that
+// case we stay in IRQ mode and use the IRQ stack instead. Otherwise this
+// works like the cortex-r-rt code, and the handler runs on the System mode
+// stack. Either way, IRQs stay masked until we return.
+#[cfg(feature = "el0-tasks")]
+core::arch::global_asm!(
+    r#"
+    .section .text._asm_irq_handler
+    .global _asm_irq_handler
+    .type _asm_irq_handler, %function
+    .arm
+_asm_irq_handler:
+    // Return to the interrupted instruction
+    sub     lr, lr, #4
+    // Did we interrupt User mode?
+    push    {{ r0 }}
+    mrs     r0, spsr
+    and     r0, r0, #0x1F
+    cmp     r0, #0x10
+    pop     {{ r0 }}
+    // If so, push LR_irq and SPSR_irq to the IRQ stack, and stay here.
+    // Otherwise push them to the System mode stack, and go there.
+    bne     1f
+    srsfd   sp!, #0x12
+    b       2f
+1:
+    srsfd   sp!, #0x1F
+    cps     #0x1F
+2:
+    // Save the caller-saved registers
+    push    {{ r0-r3, r12 }}
+    vpush   {{ s0-s15 }}
+    vmrs    r0, fpscr
+    // Align the stack to 8 bytes, remembering how much we moved it
+    and     r1, sp, #4
+    sub     sp, sp, r1
+    push    {{ r0, r1, r2, lr }}
+    bl      _irq_handler
+    pop     {{ r0, r1, r2, lr }}
+    add     sp, sp, r1
+    vmsr    fpscr, r0
+    vpop    {{ s0-s15 }}
+    pop     {{ r0-r3, r12 }}
+    // Restore the interrupted PC and CPSR (including the mode)
+    rfefd   sp!
+    .size _asm_irq_handler, . - _asm_irq_handler
+"#
+);
@@ -0,0 +1,1017 @@
+//! An EL2 monitor, which runs EL1 guests in their own partitions
+//!
+//! With the `hypervisor` feature, core 0 stays in EL2 after start-up and runs
+//! this monitor, instead of dropping straight to EL1. The monitor:
+//!
+//! * keeps its statics and stack in `.hyp.bss`, which the EL2 MPU hides from
+//!   the guests
+//! * calls `s32z2_hyp_main`, which adds guests with [`add_guest`] - by
+//!   default, the application is the only guest, with all the RAM and
+//!   peripherals it normally has
+//! * keeps the other guests' RAM apart, as no two guests can share a region.
+//!   Put their statics and stacks in `.guest.bss`, which the primary guest's
+//!   regions leave out
+//! * gives each guest its own EL2 MPU regions, which limit what that guest
+//!   can reach, whatever it does with its own (EL1) MPU
+//! * runs the guests in turn. A guest runs until it calls [`yield_now`] or
+//!   [`exit`], or until it touches memory outside its regions, which stops
+//!   it.
+//!
+//! Switching guests saves and restores the general purpose, banked and FPU
+//! registers, and each guest's EL1 MPU and system control registers. The
+//! guests share the GIC and the Generic Timer, and scheduling is cooperative,
+//! so only one guest should take interrupts.
+//!
+//! The monitor runs before `.data` and `.bss` are initialised (the primary
+//! guest does that when it starts), so it must not use them. Only core 0 runs
+//! the monitor - cores 1 to 3 still drop to EL1 on their own.
+
+use core::cell::UnsafeCell;
+
+use cortex_ar::pmsav8::{El1Mpu, El2AccessPerms, El2Config, El2Mpu, El2Region, El2Shareability};
+
+use crate::mpu::{
+    CODE_RANGE, DATA_RANGE, MEMORY_ATTRIBUTES, MPU_MAIR_INDEX_CODE, MPU_MAIR_INDEX_DATA,
+    MPU_MAIR_INDEX_DEVICE, MPU_MAIR_INDEX_SHARED, PERIPHERAL_RANGE, SHARED_RANGE,
+};
+
+/// How many guests the monitor can run
+pub const MAX_GUESTS: usize = 4;
+
+/// How many EL2 MPU regions each guest can have
+pub const MAX_GUEST_REGIONS: usize = 12;
+
+/// How many EL2 MPU regions the monitor keeps for itself
+const FIXED_REGIONS: usize = 4;
+
+/// The most EL1 MPU regions a Cortex-R52 can have
+const MAX_EL1_REGIONS: usize = 24;
+
+/// PMSAv8 regions start and end on a 64-byte boundary
+const REGION_ALIGNMENT: usize = 64;
+
+/// HVC immediate for [`exit`]
+const HVC_EXIT: u32 = 0;
+
+/// HVC immediate for [`yield_now`]
+const HVC_YIELD: u32 = 1;
+
+/// HSR exception class for an HVC instruction
+const EC_HVC: u32 = 0x12;
+
+/// HSR exception class for a Prefetch Abort from EL1 or EL0
+const EC_PREFETCH_ABORT: u32 = 0x20;
+
+/// HSR exception class for a Data Abort from EL1 or EL0
+const EC_DATA_ABORT: u32 = 0x24;
+
+/// SPSR for a guest starting in SVC mode, with IRQs and FIQs masked
+const SPSR_SVC: u32 = 0xD3;
+
+/// SPSR for a guest starting in SYS mode, with IRQs and FIQs masked
+const SPSR_SYS: u32 = 0xDF;
+
+/// FPEXC.EN, which turns the FPU on
+const FPEXC_EN: u32 = 1 << 30;
+
+/// CPACR bits giving EL1 and EL0 full access to the FPU (CP10 and CP11)
+const CPACR_FPU: u32 = 0xF << 20;
+
+/// HCR.VM, which checks EL1 and EL0 accesses against the EL2 MPU
+const HCR_VM: u32 = 1 << 0;
+
+/// Errors that can occur when setting up guests
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// There are already [`MAX_GUESTS`] guests
+    TooManyGuests,
+    /// The guest has more regions than the EL2 MPU has room for
+    TooManyRegions,
+    /// A region does not start and end on a 64-byte boundary
+    Unaligned,
+    /// A region overlaps the monitor's regions, another of the guest's, or
+    /// one belonging to another guest
+    Overlap,
+    /// The EL2 MPU rejected a region
+    Mpu,
+}
+
+/// Where a guest starts running
+#[derive(Debug, Copy, Clone)]
+pub enum Entry {
+    /// The normal start-up code, which initialises `.data` and `.bss` and then
+    /// runs `s32z2_main` at EL1
+    Primary,
+    /// A function, called in SYS mode with its stack pointer set
+    ///
+    /// The guest has no stacks for the other modes, and no vector table, so
+    /// must set those up before it takes any exceptions.
+    Function {
+        /// The guest's entry point
+        function: extern "C" fn() -> !,
+        /// The top of the guest's stack
+        stack_top: usize,
+    },
+}
+
+/// A guest for the monitor to run
+#[derive(Debug, Clone)]
+pub struct Guest<'a> {
+    /// Where the guest starts
+    pub entry: Entry,
+    /// The memory the guest can reach, on top of the code in Code RAM
+    pub regions: &'a [El2Region],
+}
+
+/// Identifies a guest
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GuestId(usize);
+
+/// Whether a guest can run
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+enum State {
+    /// No guest in this slot
+    Empty = 0,
+    /// Waiting for its turn, or running
+    Runnable,
+    /// Exited, or stopped by a fault
+    Stopped,
+}
+
+/// A guest's registers, as pushed by `_s32z2_hyp_trap_entry`
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+struct Frame {
+    fpexc: u32,
+    fpscr: u32,
+    s: [u32; 32],
+    r: [u32; 13],
+    /// Hyp mode has no LR of its own, so this is the guest's `LR_usr`
+    lr: u32,
+}
+
+/// A guest's banked registers, and its return state
+#[derive(Debug, Copy, Clone)]
+struct Banked {
+    sp_usr: u32,
+    sp_svc: u32,
+    lr_svc: u32,
+    spsr_svc: u32,
+    sp_abt: u32,
+    lr_abt: u32,
+    spsr_abt: u32,
+    sp_und: u32,
+    lr_und: u32,
+    spsr_und: u32,
+    sp_irq: u32,
+    lr_irq: u32,
+    spsr_irq: u32,
+    r8_fiq: u32,
+    r9_fiq: u32,
+    r10_fiq: u32,
+    r11_fiq: u32,
+    r12_fiq: u32,
+    sp_fiq: u32,
+    lr_fiq: u32,
+    spsr_fiq: u32,
+    /// Where the guest resumes
+    elr_hyp: u32,
+    /// The guest's CPSR, when it resumes
+    spsr_hyp: u32,
+}
+
+/// A guest's EL1 system registers
+#[derive(Debug, Copy, Clone)]
+struct El1State {
+    sctlr: u32,
+    cpacr: u32,
+    vbar: u32,
+    contextidr: u32,
+    tpidrurw: u32,
+    tpidruro: u32,
+    tpidrprw: u32,
+    mair0: u32,
+    mair1: u32,
+    prbar: [u32; MAX_EL1_REGIONS],
+    prlar: [u32; MAX_EL1_REGIONS],
+}
+
+/// Everything the monitor keeps for one guest
+#[derive(Debug, Copy, Clone)]
+struct Context {
+    frame: Frame,
+    banked: Banked,
+    el1: El1State,
+    /// The guest's EL2 MPU regions, as raw (HPRBAR, HPRLAR) values
+    el2_regions: [(u32, u32); MAX_GUEST_REGIONS],
+}
+
+/// The monitor's state
+struct Monitor {
+    contexts: [Context; MAX_GUESTS],
+    states: [State; MAX_GUESTS],
+    /// The guest that is running
+    current: usize,
+}
+
+impl Monitor {
+    /// A monitor with no guests
+    const fn new() -> Monitor {
+        // SAFETY: every field is valid as all zeroes, which matters because
+        // `.hyp.bss` is zeroed rather than loaded
+        unsafe { core::mem::zeroed() }
+    }
+}
+
+/// Holds the monitor's state, which only EL2 touches
+///
+/// This is not a `critical_section::Mutex`. The multi-core critical section
+/// takes a SEMA42 gate, which the monitor would reach through its background
+/// map as Normal memory, and a guest which trapped while holding the gate
+/// would hand it on to the next guest.
+struct MonitorCell(UnsafeCell<Monitor>);
+
+// SAFETY: only the monitor, at EL2 on core 0, touches the contents
+unsafe impl Sync for MonitorCell {}
+
+/// The monitor's state, where only EL2 can reach it
+#[link_section = ".hyp.bss"]
+static MONITOR: MonitorCell = MonitorCell(UnsafeCell::new(Monitor::new()));
+
+/// Run `f` on the monitor's state
+///
+/// Only the monitor calls this, at EL2 on core 0. Interrupts are routed to
+/// EL1, so they are never taken while the monitor runs, and the monitor never
+/// interrupts itself.
+fn with_monitor<R>(f: impl FnOnce(&mut Monitor) -> R) -> R {
+    // SAFETY: as above, so nothing else can be using the state, and `f` never
+    // calls `with_monitor` again
+    let monitor = unsafe { &mut *MONITOR.0.get() };
+    f(monitor)
+}
+
+extern "C" {
+    fn _s32z2_primary_start();
+    fn _s32z2_hyp_resume(frame: *const Frame) -> !;
+    static __core_release_start: u8;
+    static __core_release_end: u8;
+    static __hyp_start: u8;
+    static __hyp_end: u8;
+    static __guest_end: u8;
+}
+
+/// Read a coprocessor register
+macro_rules! mrc {
+    ($reg:literal) => {{
+        let value: u32;
+        core::arch::asm!(
+            concat!("mrc ", $reg),
+            out(reg) value,
+            options(nomem, nostack, preserves_flags)
+        );
+        value
+    }};
+}
+
+/// Write a coprocessor register
+macro_rules! mcr {
+    ($reg:literal, $value:expr) => {
+        core::arch::asm!(
+            concat!("mcr ", $reg),
+            in(reg) $value,
+            options(nostack, preserves_flags)
+        )
+    };
+}
+
+/// Read a banked register
+macro_rules! mrs {
+    ($reg:literal) => {{
+        let value: u32;
+        core::arch::asm!(
+            concat!("mrs {}, ", $reg),
+            out(reg) value,
+            options(nomem, nostack, preserves_flags)
+        );
+        value
+    }};
+}
+
+/// Write a banked register
+macro_rules! msr {
+    ($reg:literal, $value:expr) => {
+        core::arch::asm!(
+            concat!("msr ", $reg, ", {}"),
+            in(reg) $value,
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+}
+
+impl Frame {
+    /// The registers a new guest starts with
+    const fn new() -> Frame {
+        Frame {
+            fpexc: FPEXC_EN,
+            fpscr: 0,
+            s: [0; 32],
+            r: [0; 13],
+            lr: 0,
+        }
+    }
+}
+
+impl Banked {
+    /// Read the banked registers
+    ///
+    /// # Safety
+    ///
+    /// Only call this in Hyp mode.
+    unsafe fn save(&mut self) {
+        unsafe {
+            self.sp_usr = mrs!("sp_usr");
+            self.sp_svc = mrs!("sp_svc");
+            self.lr_svc = mrs!("lr_svc");
+            self.spsr_svc = mrs!("spsr_svc");
+            self.sp_abt = mrs!("sp_abt");
+            self.lr_abt = mrs!("lr_abt");
+            self.spsr_abt = mrs!("spsr_abt");
+            self.sp_und = mrs!("sp_und");
+            self.lr_und = mrs!("lr_und");
+            self.spsr_und = mrs!("spsr_und");
+            self.sp_irq = mrs!("sp_irq");
+            self.lr_irq = mrs!("lr_irq");
+            self.spsr_irq = mrs!("spsr_irq");
+            self.r8_fiq = mrs!("r8_fiq");
+            self.r9_fiq = mrs!("r9_fiq");
+            self.r10_fiq = mrs!("r10_fiq");
+            self.r11_fiq = mrs!("r11_fiq");
+            self.r12_fiq = mrs!("r12_fiq");
+            self.sp_fiq = mrs!("sp_fiq");
+            self.lr_fiq = mrs!("lr_fiq");
+            self.spsr_fiq = mrs!("spsr_fiq");
+            self.elr_hyp = mrs!("elr_hyp");
+            // The banked form of SPSR_hyp can't be used from Hyp mode
+            self.spsr_hyp = mrs!("spsr");
+        }
+    }
+
+    /// Write the banked registers
+    ///
+    /// # Safety
+    ///
+    /// Only call this in Hyp mode, from the monitor's exception handler.
+    unsafe fn restore(&self) {
+        unsafe {
+            msr!("sp_usr", self.sp_usr);
+            msr!("sp_svc", self.sp_svc);
+            msr!("lr_svc", self.lr_svc);
+            msr!("spsr_svc", self.spsr_svc);
+            msr!("sp_abt", self.sp_abt);
+            msr!("lr_abt", self.lr_abt);
+            msr!("spsr_abt", self.spsr_abt);
+            msr!("sp_und", self.sp_und);
+            msr!("lr_und", self.lr_und);
+            msr!("spsr_und", self.spsr_und);
+            msr!("sp_irq", self.sp_irq);
+            msr!("lr_irq", self.lr_irq);
+            msr!("spsr_irq", self.spsr_irq);
+            msr!("r8_fiq", self.r8_fiq);
+            msr!("r9_fiq", self.r9_fiq);
+            msr!("r10_fiq", self.r10_fiq);
+            msr!("r11_fiq", self.r11_fiq);
+            msr!("r12_fiq", self.r12_fiq);
+            msr!("sp_fiq", self.sp_fiq);
+            msr!("lr_fiq", self.lr_fiq);
+            msr!("spsr_fiq", self.spsr_fiq);
+            msr!("elr_hyp", self.elr_hyp);
+            msr!("spsr_fsxc", self.spsr_hyp);
+        }
+    }
+}
+
+impl El1State {
+    /// How many EL1 MPU regions this core has
+    fn num_regions() -> usize {
+        // SAFETY: we only ask how many regions there are
+        let mpu = unsafe { El1Mpu::new() };
+        usize::from(mpu.num_regions()).min(MAX_EL1_REGIONS)
+    }
+
+    /// Read the EL1 system registers
+    ///
+    /// # Safety
+    ///
+    /// Only call this at EL2, while no guest is running.
+    unsafe fn save(&mut self) {
+        unsafe {
+            self.sctlr = mrc!("p15, 0, {}, c1, c0, 0");
+            self.cpacr = mrc!("p15, 0, {}, c1, c0, 2");
+            self.vbar = mrc!("p15, 0, {}, c12, c0, 0");
+            self.contextidr = mrc!("p15, 0, {}, c13, c0, 1");
+            self.tpidrurw = mrc!("p15, 0, {}, c13, c0, 2");
+            self.tpidruro = mrc!("p15, 0, {}, c13, c0, 3");
+            self.tpidrprw = mrc!("p15, 0, {}, c13, c0, 4");
+            self.mair0 = mrc!("p15, 0, {}, c10, c2, 0");
+            self.mair1 = mrc!("p15, 0, {}, c10, c2, 1");
+            for idx in 0..Self::num_regions() {
+                mcr!("p15, 0, {}, c6, c2, 1", idx as u32);
+                cortex_ar::asm::isb();
+                self.prbar[idx] = mrc!("p15, 0, {}, c6, c3, 0");
+                self.prlar[idx] = mrc!("p15, 0, {}, c6, c3, 1");
+            }
+        }
+    }
+
+    /// Write the EL1 system registers
+    ///
+    /// # Safety
+    ///
+    /// Only call this at EL2, while no guest is running.
+    unsafe fn restore(&self) {
+        unsafe {
+            for idx in 0..Self::num_regions() {
+                mcr!("p15, 0, {}, c6, c2, 1", idx as u32);
+                cortex_ar::asm::isb();
+                mcr!("p15, 0, {}, c6, c3, 0", self.prbar[idx]);
+                mcr!("p15, 0, {}, c6, c3, 1", self.prlar[idx]);
+            }
+            mcr!("p15, 0, {}, c10, c2, 0", self.mair0);
+            mcr!("p15, 0, {}, c10, c2, 1", self.mair1);
+            mcr!("p15, 0, {}, c13, c0, 1", self.contextidr);
+            mcr!("p15, 0, {}, c13, c0, 2", self.tpidrurw);
+            mcr!("p15, 0, {}, c13, c0, 3", self.tpidruro);
+            mcr!("p15, 0, {}, c13, c0, 4", self.tpidrprw);
+            mcr!("p15, 0, {}, c12, c0, 0", self.vbar);
+            mcr!("p15, 0, {}, c1, c0, 2", self.cpacr);
+            mcr!("p15, 0, {}, c1, c0, 0", self.sctlr);
+        }
+        cortex_ar::asm::dsb();
+        cortex_ar::asm::isb();
+    }
+}
+
+impl Context {
+    /// Save the state of the guest that just trapped
+    ///
+    /// # Safety
+    ///
+    /// Only call this from the monitor's exception handler.
+    unsafe fn save(&mut self, frame: &Frame) {
+        self.frame = *frame;
+        unsafe {
+            self.banked.save();
+            self.el1.save();
+        }
+    }
+
+    /// Load the state of a guest, apart from its `Frame`
+    ///
+    /// # Safety
+    ///
+    /// Only call this from the monitor, at EL2.
+    unsafe fn load(&self) {
+        // SAFETY: we only ask how many regions there are
+        let num_regions = usize::from(unsafe { El2Mpu::new() }.num_regions());
+        let slots = num_regions.saturating_sub(FIXED_REGIONS);
+        unsafe {
+            self.el1.restore();
+            self.banked.restore();
+            for (idx, (prbar, prlar)) in self.el2_regions.iter().take(slots).enumerate() {
+                write_el2_region(FIXED_REGIONS + idx, *prbar, *prlar);
+            }
+        }
+        cortex_ar::asm::dsb();
+        cortex_ar::asm::isb();
+    }
+}
+
+/// Read an EL2 MPU region, as raw (HPRBAR, HPRLAR) values
+///
+/// # Safety
+///
+/// Only call this at EL2.
+unsafe fn read_el2_region(idx: usize) -> (u32, u32) {
+    unsafe {
+        mcr!("p15, 4, {}, c6, c2, 1", idx as u32);
+        cortex_ar::asm::isb();
+        (mrc!("p15, 4, {}, c6, c3, 0"), mrc!("p15, 4, {}, c6, c3, 1"))
+    }
+}
+
+/// The addresses an encoded EL2 MPU region covers, if it is enabled
+fn el2_bounds(&(prbar, prlar): &(u32, u32)) -> Option<(usize, usize)> {
+    // HPRLAR.EN is bit 0, and both registers hold a 64-byte aligned address
+    if prlar & 1 == 0 {
+        return None;
+    }
+    let mask = !(REGION_ALIGNMENT as u32 - 1);
+    Some(((prbar & mask) as usize, (prlar | !mask) as usize))
+}
+
+/// Write an EL2 MPU region from raw (HPRBAR, HPRLAR) values
+///
+/// # Safety
+///
+/// Only call this at EL2, while no guest is running.
+unsafe fn write_el2_region(idx: usize, prbar: u32, prlar: u32) {
+    unsafe {
+        mcr!("p15, 4, {}, c6, c2, 1", idx as u32);
+        cortex_ar::asm::isb();
+        mcr!("p15, 4, {}, c6, c3, 0", prbar);
+        mcr!("p15, 4, {}, c6, c3, 1", prlar);
+    }
+}
+
+/// The EL2 MPU regions the monitor keeps for itself
+///
+/// * Code RAM is read-only, and only `.text` is executable, as in the EL1
+///   map. The flag which releases the other cores is read-write.
+/// * The monitor's own RAM is only reachable from EL2.
+fn fixed_regions() -> [El2Region; FIXED_REGIONS] {
+    let addr = |sym: &u8| core::ptr::from_ref(sym) as usize;
+    // SAFETY: we only take the addresses of these linker symbols
+    let (release_start, release_end, hyp_start, hyp_end) = unsafe {
+        (
+            addr(&__core_release_start),
+            addr(&__core_release_end),
+            addr(&__hyp_start),
+            addr(&__hyp_end),
+        )
+    };
+    let text_access = if cfg!(feature = "debug-breakpoints") {
+        El2AccessPerms::ReadWrite
+    } else {
+        El2AccessPerms::ReadOnly
+    };
+    [
+        // Vectors and .text in Code RAM
+        El2Region {
+            range: (*CODE_RANGE.start() as *mut u8)..=((release_start - 1) as *mut u8),
+            shareability: El2Shareability::InnerShareable,
+            access: text_access,
+            no_exec: false,
+            mair: MPU_MAIR_INDEX_CODE,
+            enable: true,
+        },
+        // The core release flag in Code RAM
+        El2Region {
+            range: (release_start as *mut u8)..=((release_end - 1) as *mut u8),
+            shareability: El2Shareability::InnerShareable,
+            access: El2AccessPerms::ReadWrite,
+            no_exec: true,
+            mair: MPU_MAIR_INDEX_CODE,
+            enable: true,
+        },
+        // .rodata and the .data load image in Code RAM
+        El2Region {
+            range: (release_end as *mut u8)..=(*CODE_RANGE.end() as *mut u8),
+            shareability: El2Shareability::InnerShareable,
+            access: El2AccessPerms::ReadOnly,
+            no_exec: true,
+            mair: MPU_MAIR_INDEX_CODE,
+            enable: true,
+        },
+        // The monitor's statics and stack
+        El2Region {
+            range: (hyp_start as *mut u8)..=((hyp_end - 1) as *mut u8),
+            shareability: El2Shareability::InnerShareable,
+            access: El2AccessPerms::ReadWriteNoEL10,
+            no_exec: true,
+            mair: MPU_MAIR_INDEX_DATA,
+            enable: true,
+        },
+    ]
+}
+
+/// The regions the application needs, to run as the primary guest
+///
+/// This is all of Data RAM (apart from the monitor's own, and `.guest.bss`
+/// above it), the Shared RAM, the peripherals and the GIC.
+pub fn primary_regions() -> [El2Region; 4] {
+    let hyp_start = core::ptr::addr_of!(__hyp_start) as usize;
+    let guest_end = core::ptr::addr_of!(__guest_end) as usize;
+    let region = |start: usize, end: usize, mair: u8| El2Region {
+        range: (start as *mut u8)..=(end as *mut u8),
+        shareability: if mair == MPU_MAIR_INDEX_DEVICE {
+            El2Shareability::NonShareable
+        } else {
+            El2Shareability::InnerShareable
+        },
+        access: El2AccessPerms::ReadWrite,
+        no_exec: true,
+        mair,
+        enable: true,
+    };
+    [
+        // Data RAM, either side of the monitor and the other guests
+        region(*DATA_RANGE.start(), hyp_start - 1, MPU_MAIR_INDEX_DATA),
+        region(guest_end, *DATA_RANGE.end(), MPU_MAIR_INDEX_DATA),
+        // Shared data in the cluster's Shared RAM
+        region(
+            *SHARED_RANGE.start(),
+            *SHARED_RANGE.end(),
+            MPU_MAIR_INDEX_SHARED,
+        ),
+        // The peripherals and the GIC
+        region(
+            *PERIPHERAL_RANGE.start(),
+            *PERIPHERAL_RANGE.end(),
+            MPU_MAIR_INDEX_DEVICE,
+        ),
+    ]
+}
+
+/// Add a guest for the monitor to run
+///
+/// Call this from `s32z2_hyp_main`. Guests run in the order they were added.
+/// The guest's regions must start and end on a 64-byte boundary, and must not
+/// overlap each other, the monitor's regions, or any other guest's regions.
+pub fn add_guest(guest: &Guest<'_>) -> Result<GuestId, Error> {
+    // SAFETY: the EL2 MPU isn't on yet, and we only touch the guest regions
+    let mut mpu = unsafe { El2Mpu::new() };
+    let num_regions = usize::from(mpu.num_regions());
+    if guest.regions.len() > MAX_GUEST_REGIONS || FIXED_REGIONS + guest.regions.len() > num_regions
+    {
+        return Err(Error::TooManyRegions);
+    }
+    let bounds =
+        |region: &El2Region| (*region.range.start() as usize, *region.range.end() as usize);
+    let fixed = fixed_regions();
+    for (idx, region) in guest.regions.iter().enumerate() {
+        let (start, end) = bounds(region);
+        if start % REGION_ALIGNMENT != 0 || (end + 1) % REGION_ALIGNMENT != 0 || end < start {
+            return Err(Error::Unaligned);
+        }
+        for other in fixed.iter().chain(&guest.regions[..idx]) {
+            let (other_start, other_end) = bounds(other);
+            if start <= other_end && other_start <= end {
+                return Err(Error::Overlap);
+            }
+        }
+    }
+
+    with_monitor(|monitor| {
+        let id = monitor
+            .states
+            .iter()
+            .position(|state| *state == State::Empty)
+            .ok_or(Error::TooManyGuests)?;
+        // A guest's memory is its own, so nobody else's regions can cover it
+        let others = monitor
+            .contexts
+            .iter()
+            .zip(&monitor.states)
+            .filter(|(_, state)| **state != State::Empty)
+            .flat_map(|(context, _)| context.el2_regions.iter().filter_map(el2_bounds));
+        for (other_start, other_end) in others {
+            if guest.regions.iter().any(|region| {
+                let (start, end) = bounds(region);
+                start <= other_end && other_start <= end
+            }) {
+                return Err(Error::Overlap);
+            }
+        }
+        let context = &mut monitor.contexts[id];
+        // Have the EL2 MPU encode the regions, in the slots the guest will use
+        context.el2_regions = [(0, 0); MAX_GUEST_REGIONS];
+        for (idx, region) in guest.regions.iter().enumerate() {
+            let slot = FIXED_REGIONS + idx;
+            mpu.set_region(slot as u8, region).map_err(|_| Error::Mpu)?;
+            // SAFETY: we are at EL2, and no guest is running
+            context.el2_regions[idx] = unsafe { read_el2_region(slot) };
+        }
+        // Guests start with EL1 as it came out of reset
+        // SAFETY: we are at EL2, and no guest is running
+        unsafe { context.el1.save() };
+        context.frame = Frame::new();
+        match guest.entry {
+            Entry::Primary => {
+                context.banked.elr_hyp = _s32z2_primary_start as *const () as usize as u32;
+                context.banked.spsr_hyp = SPSR_SVC;
+            }
+            Entry::Function {
+                function,
+                stack_top,
+            } => {
+                context.banked.elr_hyp = function as usize as u32;
+                context.banked.spsr_hyp = SPSR_SYS;
+                context.banked.sp_usr = stack_top as u32;
+                // The primary start-up code turns the FPU on, but we must do
+                // it for anything else
+                context.el1.cpacr |= CPACR_FPU;
+            }
+        }
+        monitor.states[id] = State::Runnable;
+        Ok(GuestId(id))
+    })
+}
+
+/// Give up the CPU to the next guest
+///
+/// Call this from a guest, at EL1.
+pub fn yield_now() {
+    // SAFETY: the monitor preserves every register
+    unsafe {
+        core::arch::asm!("hvc #{}", const HVC_YIELD);
+    }
+}
+
+/// Stop this guest
+///
+/// Call this from a guest, at EL1.
+pub fn exit() -> ! {
+    // SAFETY: the monitor never returns to a guest which has exited
+    unsafe {
+        core::arch::asm!("hvc #{}", const HVC_EXIT, options(noreturn));
+    }
+}
+
+/// Turn on the EL2 MPU, and run the guests
+fn run() -> ! {
+    // SAFETY: this is the only EL2 MPU driver, and no guest is running
+    let mut mpu = unsafe { El2Mpu::new() };
+    let fixed = fixed_regions();
+    let config = El2Config {
+        // The monitor itself can reach everything else
+        background_config: true,
+        regions: &fixed,
+        memory_attributes: &MEMORY_ATTRIBUTES,
+    };
+    mpu.configure(&config).expect("EL2 MPU Config");
+    mpu.enable();
+    // Check EL1 and EL0 accesses against the EL2 MPU too
+    // SAFETY: every guest only runs with its own regions loaded
+    unsafe {
+        let hcr = mrc!("p15, 4, {}, c1, c1, 0");
+        mcr!("p15, 4, {}, c1, c1, 0", hcr | HCR_VM);
+    }
+    cortex_ar::asm::isb();
+
+    let frame = with_monitor(|monitor| {
+        let first = monitor
+            .states
+            .iter()
+            .position(|state| *state == State::Runnable)?;
+        monitor.current = first;
+        // SAFETY: we are at EL2, and no guest is running
+        unsafe { monitor.contexts[first].load() };
+        Some(core::ptr::addr_of!(monitor.contexts[first].frame))
+    });
+    match frame {
+        // SAFETY: the frame lives in a static, not on the stack we're about
+        // to throw away
+        Some(frame) => unsafe { _s32z2_hyp_resume(frame) },
+        None => park("No guests to run"),
+    }
+}
+
+/// Stop the current guest, or let it wait, and pick the next one to run
+fn switch(frame: &mut Frame, state: State) {
+    let next = with_monitor(|monitor| {
+        let current = monitor.current;
+        monitor.states[current] = state;
+        // Round-robin, coming back to this guest last
+        let next = (1..=MAX_GUESTS)
+            .map(|offset| (current + offset) % MAX_GUESTS)
+            .find(|idx| monitor.states[*idx] == State::Runnable)?;
+        if next != current {
+            // SAFETY: we are in the monitor's exception handler
+            unsafe {
+                monitor.contexts[current].save(frame);
+                monitor.contexts[next].load();
+            }
+            *frame = monitor.contexts[next].frame;
+            monitor.current = next;
+        }
+        Some(next)
+    });
+    if next.is_none() {
+        park("All guests have stopped");
+    }
+}
+
+/// Wait forever, as there is nothing left to run
+fn park(reason: &str) -> ! {
+    arm_dcc::dprintln!("Monitor: {}", reason);
+    loop {
+        cortex_ar::asm::wfi();
+    }
+}
+
+/// The guest which is running
+fn current() -> usize {
+    with_monitor(|monitor| monitor.current)
+}
+
+/// The default `s32z2_hyp_main`, which runs the application as the only
+/// guest
+///
+/// The linker script points `s32z2_hyp_main` here if the application doesn't
+/// provide one.
+#[no_mangle]
+fn _s32z2_default_hyp_main() {
+    add_guest(&Guest {
+        entry: Entry::Primary,
+        regions: &primary_regions(),
+    })
+    .expect("Primary guest");
+}
+
+/// The entry-point to the monitor, called by `_s32z2_el2_start`
+#[no_mangle]
+extern "C" fn _s32z2_hyp_entry() -> ! {
+    unsafe extern "Rust" {
+        safe fn s32z2_hyp_main();
+    }
+    s32z2_hyp_main();
+    run()
+}
+
+/// Handle a trap from a guest
+///
+/// Called by `_s32z2_hyp_trap_entry`, with the guest's registers. Whatever is
+/// in `frame` when this returns is loaded into the registers of the guest we
+/// return to.
+#[no_mangle]
+extern "C" fn _s32z2_hyp_trap(frame: &mut Frame) {
+    // SAFETY: reading HSR, HIFAR, HDFAR and ELR_hyp has no side-effects
+    let (hsr, elr) = unsafe { (mrc!("p15, 4, {}, c5, c2, 0"), mrs!("elr_hyp")) };
+    match hsr >> 26 {
+        EC_HVC => match hsr & 0xFFFF {
+            HVC_EXIT => switch(frame, State::Stopped),
+            HVC_YIELD => switch(frame, State::Runnable),
+            // Unknown call
+            _ => frame.r[0] = u32::MAX,
+        },
+        EC_PREFETCH_ABORT => {
+            let address = unsafe { mrc!("p15, 4, {}, c6, c0, 2") };
+            arm_dcc::dprintln!(
+                "Monitor: guest {} fetched from {:#010x}, outside its regions",
+                current(),
+                address
+            );
+            switch(frame, State::Stopped);
+        }
+        EC_DATA_ABORT => {
+            let address = unsafe { mrc!("p15, 4, {}, c6, c0, 0") };
+            arm_dcc::dprintln!(
+                "Monitor: guest {} accessed {:#010x} at PC {:#010x}, outside its regions",
+                current(),
+                address,
+                elr
+            );
+            switch(frame, State::Stopped);
+        }
+        _ => {
+            arm_dcc::dprintln!(
+                "Monitor: guest {} trapped with HSR {:#010x} at PC {:#010x}",
+                current(),
+                hsr,
+                elr
+            );
+            switch(frame, State::Stopped);
+        }
+    }
+}
+
+/// Handle an exception taken by the monitor itself
+#[no_mangle]
+extern "C" fn _s32z2_hyp_fault(hsr: u32) -> ! {
+    // SAFETY: reading ELR_hyp has no side-effects
+    let elr = unsafe { mrs!("elr_hyp") };
+    arm_dcc::dprintln!("Monitor: HSR {:#010x} at PC {:#010x}", hsr, elr);
+    park("Monitor faulted");
+}
+
+// Start-up and exception entry code for the EL2 monitor
+//
+// `_s32z2_el2_start` replaces `_default_start` on core 0. It stays in EL2,
+// sets up the monitor's stack and vector table, and calls into Rust. The
+// primary guest starts at `_s32z2_primary_start`, which does what
+// `_default_start` does once it has left EL2 - `_default_start` itself only
+// finds the top of the stacks on its way out of EL2, so can't be entered at
+// EL1. There is no HYP stack, so the guest's stacks start at `_stack_top`. The Hyp
+// trap entry saves everything a guest switch has to swap into a `Frame` on
+// the monitor's stack, and `_s32z2_hyp_resume` enters a guest by loading a
+// `Frame` the same way.
+#[cfg(target_arch = "arm")]
+core::arch::global_asm!(
+    r#"
+    .section .text._s32z2_el2_start
+    .global _s32z2_el2_start
+    .type _s32z2_el2_start, %function
+    _s32z2_el2_start:
+        // Only stay in EL2 if we are in Hyp mode
+        mrs     r0, cpsr
+        and     r0, r0, #0x1F
+        cmp     r0, #0x1A
+        bne     _default_start
+
+        // Zero the monitor's statics and stack, and use that stack
+        ldr     r0, =__hyp_start
+        ldr     r1, =__hyp_end
+        mov     r2, #0
+    .Lhyp_zero_loop:
+        cmp     r0, r1
+        strlo   r2, [r0], #4
+        blo     .Lhyp_zero_loop
+        ldr     sp, =__hyp_stack_top
+
+        // Take traps from the guests
+        ldr     r0, =_s32z2_hyp_vector_table
+        mcr     p15, 4, r0, c12, c0, 0 /* Write HVBAR */
+        mrc     p15, 4, r0, c1, c0, 1 /* Read HACTLR */
+        ldr     r1, =0xF83            /* EL1 access to CPUACTLR, CDBGDCI, PERIPHPREGIONR, QOSR, etc */
+        orr     r0, r0, r1
+        mcr     p15, 4, r0, c1, c0, 1 /* Write HACTLR */
+
+        // Don't trap FPU use to EL2, and turn the FPU on
+        mov     r0, #0
+        mcr     p15, 4, r0, c1, c1, 2 /* Write HCPTR */
+        isb
+        mov     r0, #{fpexc_en}
+        vmsr    fpexc, r0
+
+        bl      _s32z2_hyp_entry
+    .Lhyp_start_park:
+        wfi
+        b       .Lhyp_start_park
+
+    .section .text._s32z2_hyp_vector_table
+    .align 5
+    .global _s32z2_hyp_vector_table
+    _s32z2_hyp_vector_table:
+        b       .Lhyp_unexpected      /* Reserved */
+        b       .Lhyp_unexpected      /* Undefined Instruction, in Hyp mode */
+        b       .Lhyp_unexpected      /* HVC, in Hyp mode */
+        b       .Lhyp_unexpected      /* Prefetch Abort, in Hyp mode */
+        b       .Lhyp_unexpected      /* Data Abort, in Hyp mode */
+        b       _s32z2_hyp_trap_entry /* Hyp Trap, from a guest */
+        b       .Lhyp_unexpected      /* IRQ (guests take their own) */
+        b       .Lhyp_unexpected      /* FIQ (guests take their own) */
+    .Lhyp_unexpected:
+        mrc     p15, 4, r0, c5, c2, 0 /* Read HSR */
+        bl      _s32z2_hyp_fault
+
+    .section .text._s32z2_hyp_trap_entry
+    .global _s32z2_hyp_trap_entry
+    .type _s32z2_hyp_trap_entry, %function
+    _s32z2_hyp_trap_entry:
+        // Build a Frame on our stack: FPEXC, FPSCR, s0-s31, r0-r12 and LR
+        push    {{r0-r12, lr}}
+        vmrs    r0, fpexc
+        orr     r1, r0, #{fpexc_en}   /* The guest may have turned the FPU off */
+        vmsr    fpexc, r1
+        vpush   {{s16-s31}}
+        vpush   {{s0-s15}}
+        vmrs    r1, fpscr
+        push    {{r0, r1}}
+        mov     r0, sp
+        bl      _s32z2_hyp_trap
+    .Lhyp_trap_return:
+        pop     {{r0, r1}}
+        vmsr    fpscr, r1
+        vpop    {{s0-s15}}
+        vpop    {{s16-s31}}
+        vmsr    fpexc, r0
+        pop     {{r0-r12, lr}}
+        eret
+
+    .section .text._s32z2_primary_start
+    .global _s32z2_primary_start
+    .type _s32z2_primary_start, %function
+    _s32z2_primary_start:
+        ldr     r0, =_stack_top
+        bl      _stack_setup
+        ldr     r0, =_vector_table
+        mcr     p15, 0, r0, c12, c0, 0 /* Write VBAR */
+        bl      _init_segments
+        mrc     p15, 0, r0, c1, c0, 2 /* Read CPACR */
+        orr     r0, r0, #0xF00000     /* Full access to CP10 and CP11 */
+        mcr     p15, 0, r0, c1, c0, 2 /* Write CPACR */
+        mov     r0, #{fpexc_en}
+        vmsr    fpexc, r0
+        bl      kmain
+        b       .
+
+    .section .text._s32z2_hyp_resume
+    .global _s32z2_hyp_resume
+    .type _s32z2_hyp_resume, %function
+    _s32z2_hyp_resume:
+        // Copy the Frame in r0 to the top of our stack, then load it
+        ldr     sp, =__hyp_stack_top
+        sub     sp, sp, #{frame_size}
+        mov     r1, sp
+        mov     r2, #{frame_size}
+    .Lhyp_resume_loop:
+        ldr     r3, [r0], #4
+        str     r3, [r1], #4
+        subs    r2, r2, #4
+        bne     .Lhyp_resume_loop
+        b       .Lhyp_trap_return
+    "#,
+    fpexc_en = const FPEXC_EN,
+    frame_size = const core::mem::size_of::<Frame>(),
+);
@@ -0,0 +1,34 @@
+//! Interrupt numbers for the S32Z2 RTU0 GIC
+//!
+//! Each one converts into an [`IntId`], so you can pass them to anything in
+//! [`crate::gic`].
+//!
+//! This only lists interrupts whose numbers we have been able to check. The
+//! peripheral interrupts (SPIs) belong here too, but their numbers have to come
+//! from the RTU interrupt map that comes with the S32Z2 Reference Manual. Until
+//! they are added from that, use [`IntId::spi`] with the number from the map.
+
+use arm_gic::IntId;
+
+/// An interrupt, as seen by the RTU0 GIC
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Interrupt {
+    /// The EL1 Virtual Timer, PPI 11 (INTID 27), according to the Cortex-R52
+    /// Reference Manual
+    VirtualTimer,
+}
+
+impl Interrupt {
+    /// The GIC Interrupt ID
+    pub const fn int_id(self) -> IntId {
+        match self {
+            Interrupt::VirtualTimer => IntId::ppi(11),
+        }
+    }
+}
+
+impl From<Interrupt> for IntId {
+    fn from(interrupt: Interrupt) -> IntId {
+        interrupt.int_id()
+    }
+}
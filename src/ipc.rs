@@ -0,0 +1,132 @@
+//! Inter-core messaging for the S32Z2
+//!
+//! A channel is a [`RingBuffer`] in the shared, non-cacheable, `.shared`
+//! section, plus a [`Doorbell`] that the sender rings to wake the receiver.
+//! On hardware the doorbell is a GIC Software Generated Interrupt, sent to the
+//! receiving core.
+//!
+//! Declare the buffer like this, so that both cores see the same memory:
+//!
+//! ```rust,ignore
+//! #[link_section = ".shared"]
+//! static CHANNEL: RingBuffer<u32, 16> = RingBuffer::new();
+//! ```
+
+use arm_gic::{
+    gicv3::{GicCpuInterface, GicError, SgiTarget, SgiTargetGroup},
+    IntId,
+};
+
+use crate::gic::NUM_CORES;
+pub use crate::ring_buffer::{Consumer, Producer, RingBuffer};
+
+/// Errors that can occur when ringing a doorbell
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// That core does not exist
+    NoSuchCore,
+    /// The GIC would not send the SGI
+    Sgi(GicError),
+}
+
+/// Errors that can occur when sending on a channel
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SendError<T> {
+    /// The channel is full, so here is the value back. The doorbell was not
+    /// rung.
+    Full(T),
+    /// The value is in the channel, but the doorbell could not be rung, so
+    /// the receiver may not know about it yet
+    Doorbell(Error),
+}
+
+/// Something that can wake up the receiving end of a channel
+pub trait Doorbell {
+    /// Tell the receiver there is something for it
+    fn ring(&self) -> Result<(), Error>;
+}
+
+/// A doorbell that sends an SGI to another core in this cluster
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SgiDoorbell {
+    /// The SGI to send. The receiving core must have it enabled.
+    pub sgi: IntId,
+    /// The core to send it to (0 to 3)
+    pub core: u8,
+}
+
+impl Doorbell for SgiDoorbell {
+    fn ring(&self) -> Result<(), Error> {
+        if usize::from(self.core) >= NUM_CORES {
+            return Err(Error::NoSuchCore);
+        }
+        GicCpuInterface::send_sgi(
+            self.sgi,
+            SgiTarget::List {
+                affinity3: 0,
+                affinity2: 0,
+                affinity1: 0,
+                target_list: 1 << self.core,
+            },
+            SgiTargetGroup::CurrentGroup1,
+        )
+        .map_err(Error::Sgi)
+    }
+}
+
+/// The sending end of a channel
+pub struct Sender<'a, T, const N: usize, D> {
+    producer: Producer<'a, T, N>,
+    doorbell: D,
+}
+
+impl<'a, T: Copy, const N: usize, D: Doorbell> Sender<'a, T, N, D> {
+    /// Create the sending end of a channel
+    ///
+    /// # Safety
+    ///
+    /// There must only be one sender for this buffer at any time, on any core.
+    pub unsafe fn new(ring: &'a RingBuffer<T, N>, doorbell: D) -> Sender<'a, T, N, D> {
+        Sender {
+            producer: unsafe { ring.producer() },
+            doorbell,
+        }
+    }
+
+    /// Send a value, and ring the doorbell
+    ///
+    /// Gives the value back if the channel is full. The doorbell is not rung
+    /// in that case.
+    pub fn try_send(&mut self, value: T) -> Result<(), SendError<T>> {
+        self.producer.push(value).map_err(SendError::Full)?;
+        // make sure the value is visible before the receiver wakes up
+        cortex_ar::asm::dsb();
+        self.doorbell.ring().map_err(SendError::Doorbell)
+    }
+}
+
+/// The receiving end of a channel
+pub struct Receiver<'a, T, const N: usize> {
+    consumer: Consumer<'a, T, N>,
+}
+
+impl<'a, T: Copy, const N: usize> Receiver<'a, T, N> {
+    /// Create the receiving end of a channel
+    ///
+    /// # Safety
+    ///
+    /// There must only be one receiver for this buffer at any time, on any
+    /// core.
+    pub unsafe fn new(ring: &'a RingBuffer<T, N>) -> Receiver<'a, T, N> {
+        Receiver {
+            consumer: unsafe { ring.consumer() },
+        }
+    }
+
+    /// Take the oldest value from the channel, if any
+    ///
+    /// Call this from the doorbell interrupt handler until it returns `None`.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.consumer.pop()
+    }
+}
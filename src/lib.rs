@@ -1,27 +1,152 @@
 //! Common code for all S32Z2 examples
+//!
+//! Core 0 of RTU0 runs `s32z2_main`. If the other cores are running (in
+//! split-lock mode), core N runs `s32z2_main_coreN`, which defaults to doing
+//! nothing. Each of those cores has its own stacks, plus its own `.data` and
+//! `.bss` for statics placed in the `.coreN.data` and `.coreN.bss` sections.
+//! Statics placed in the `.shared` section are visible to every core, are not
+//! cached, and start as all zeroes.
 
 #![no_std]
 
-// Need this to bring in the start-up function
+#[cfg(target_arch = "arm")]
+use core::sync::atomic::{AtomicU32, Ordering};
 
+// Need this to bring in the start-up function
+#[cfg(target_arch = "arm")]
 use cortex_r_rt as _;
+#[cfg(target_arch = "arm")]
 use panic_dcc as _;
 
-mod clocks;
-mod mpu;
+pub mod alarm_queue;
+pub mod clocks;
+#[cfg(all(target_arch = "arm", feature = "embassy"))]
+pub mod embassy;
+#[cfg(target_arch = "arm")]
+pub mod gic;
+#[cfg(all(target_arch = "arm", feature = "hypervisor"))]
+pub mod hyp;
+pub mod interrupt;
+#[cfg(target_arch = "arm")]
+pub mod ipc;
+pub mod mc_cgm;
+#[cfg(target_arch = "arm")]
+pub mod mc_me;
+#[cfg(target_arch = "arm")]
+pub mod mc_rgm;
+#[cfg(target_arch = "arm")]
+pub mod mpu;
+#[cfg(target_arch = "arm")]
+pub mod mru;
+pub mod ring_buffer;
+#[cfg(target_arch = "arm")]
+pub mod sema42;
+pub mod stacks;
+#[cfg(all(target_arch = "arm", feature = "el0-tasks"))]
+pub mod task;
+pub mod ticks;
+#[cfg(target_arch = "arm")]
+pub mod time;
+
+#[cfg(all(
+    feature = "critical-section-single-core",
+    feature = "critical-section-multi-core"
+))]
+compile_error!("Only one of the critical-section-* features can be enabled");
+
+// Nested interrupt handlers have to run in System mode, which shares its stack
+// pointer with User mode, so they would push kernel state onto an EL0 task's
+// stack. The el0-tasks IRQ entry code stays on the IRQ stack instead.
+#[cfg(all(feature = "el0-tasks", feature = "nested-irq"))]
+compile_error!("The el0-tasks feature can't be used with nested-irq");
+
+/// Set to non-zero by core 0 once it has done the start-up work that the
+/// other cores depend on.
+///
+/// Lives in Code RAM, which the debugger loads, so the other cores can read
+/// it before core 0 has initialised the ECC on the Data RAM.
+#[cfg(target_arch = "arm")]
+#[export_name = "_core_release"]
+#[link_section = ".core_release"]
+static CORE_RELEASE: AtomicU32 = AtomicU32::new(0);
 
 /// The entry-point to the Rust application.
+#[cfg(target_arch = "arm")]
 #[cortex_r_rt::entry]
 fn kmain() -> ! {
     unsafe extern "Rust" {
         safe fn s32z2_main();
     }
     setup_core();
+    setup_soc();
+    // Let any other cores continue their start-up
+    CORE_RELEASE.store(1, Ordering::Release);
+    cortex_ar::asm::dsb();
+    cortex_ar::asm::sev();
     s32z2_main();
     semihosting::process::exit(0);
 }
 
-/// Setup RTU0 Core 1
+/// The entry-point to the Rust application on cores 1 to 3.
+///
+/// It is called by the start-up code below, with the stacks set up and this
+/// core's `.data` and `.bss` initialised.
+#[cfg(target_arch = "arm")]
+#[no_mangle]
+extern "C" fn kmain_secondary(core_id: u32) -> ! {
+    unsafe extern "Rust" {
+        safe fn s32z2_main_core1();
+        safe fn s32z2_main_core2();
+        safe fn s32z2_main_core3();
+    }
+    setup_core();
+    match core_id {
+        1 => s32z2_main_core1(),
+        2 => s32z2_main_core2(),
+        3 => s32z2_main_core3(),
+        _ => {}
+    }
+    loop {
+        cortex_ar::asm::wfi();
+    }
+}
+
+/// The default entry-point for cores 1 to 3, if the application doesn't
+/// provide one.
+///
+/// The linker script points any missing `s32z2_main_coreN` here.
+#[no_mangle]
+fn _s32z2_default_core_main() {}
+
+/// Which core in the cluster we are running on (0 to 3)
+pub fn core_id() -> usize {
+    (cortex_ar::register::Mpidr::read().0 & 0xFF) as usize
+}
+
+/// Start one of the other RTU0 cores
+///
+/// The core starts at our vector table, so it runs through the start-up code
+/// and then calls its `s32z2_main_coreN` function. The cores must be in
+/// split-lock mode for this to make sense.
+///
+/// # Safety
+///
+/// The core must not already be running.
+#[cfg(target_arch = "arm")]
+pub unsafe fn start_core(core: mc_me::Core) -> Result<(), mc_me::Error> {
+    unsafe extern "C" {
+        static _vector_table: u32;
+    }
+    let mut mc_me = unsafe { mc_me::mc_me() };
+    let mut rgm = unsafe { mc_rgm::mc_rgm() };
+    let entry = core::ptr::addr_of!(_vector_table) as usize;
+    unsafe { mc_me::start_core(&mut mc_me, &mut rgm, mc_me::Partition::Rtu0, core, entry) }
+}
+
+/// Setup this RTU0 core
+///
+/// Runs on every core.
+#[cfg(target_arch = "arm")]
 fn setup_core() {
     // Enable the peripheral port in EL1
     let mut reg = cortex_ar::register::ImpPeriphpregionr::read();
@@ -40,9 +165,41 @@ fn setup_core() {
     cortex_ar::asm::dsb();
     cortex_ar::asm::isb();
     // Need the MPU be able to talk to the clock peripheral
-    mpu::enable();
+    mpu::enable().expect("MPU Config");
+}
+
+/// Setup the parts of the SoC shared by every core
+///
+/// Runs only on core 0.
+#[cfg(target_arch = "arm")]
+fn setup_soc() {
+    // Report (and then clear) why we reset
+    let mut rgm = unsafe { mc_rgm::mc_rgm() };
+    let reset_status = mc_rgm::reset_status(&mut rgm);
+    arm_dcc::dprintln!(
+        "Reset reason: {:?} ({:?}, {:?})",
+        reset_status.reason(),
+        reset_status.des,
+        reset_status.fes
+    );
+    mc_rgm::clear_reset_status(&mut rgm, &reset_status);
     // Turn on the PLLs
-    clocks::configure_pll();
+    //
+    // SAFETY: Only this core is running, and `configure_pll` moves it to FIRC
+    // if the CORE_DFS needs reprogramming.
+    let clocks =
+        unsafe { clocks::configure_pll(&clocks::ClockConfig::DEFAULT) }.expect("Clock config");
+    arm_dcc::dprintln!("Clocks: {:?}", clocks);
+    // Check the start-up code got the Generic Timer frequency right
+    match clocks::check_counter_hz(&clocks) {
+        Ok(hz) => arm_dcc::dprintln!("Generic Timer: {} Hz", hz),
+        Err(e) => {
+            arm_dcc::dprintln!("**********************************************");
+            arm_dcc::dprintln!("WARNING: Generic Timer frequency is wrong!");
+            arm_dcc::dprintln!("{:?}", e);
+            arm_dcc::dprintln!("**********************************************");
+        }
+    }
 }
 
 // Custom start-up code for S32Z2
@@ -51,7 +208,9 @@ fn setup_core() {
 //
 // * Erases the memory, so that we don't get ECC errors
 // * Initialises the TCMs
-// * Configures the Frequency register for the Generic Timer to 8 MHz
+// * Configures the Frequency register for the Generic Timer to `COUNTER_HZ`
+// * Sends cores 1 to 3 down their own start-up path, with their own stacks,
+//   and without re-initialising the shared `.data` and `.bss`
 #[cfg(target_arch = "arm")]
 core::arch::global_asm!(
     r#"
@@ -60,54 +219,169 @@ core::arch::global_asm!(
 
     .global _start
     _start:
-        // ECC init for S32Z2, which uses a table of (start, len)
+        // Which core are we? Only core 0 does the one-off set-up.
+        mrc     p15, 0, r3, c0, c0, 5 /* Read MPIDR */
+        and     r3, r3, #0xFF         /* Keep Aff0, the core in the cluster */
+        cmp     r3, #0
+        bne     .Lsecondary_start
+
+        // Hold the other cores until we are ready. The flag lives in Code RAM,
+        // so it may still be set from before a reset.
+        ldr     r0, =_core_release
+        mov     r1, #0
+        str     r1, [r0]
+        dsb
 
-        // r4 is the address of the current entry in the table.
-        // Initialise it to the start of the table.
+        // ECC init for S32Z2, which uses a table of (start, len, flags),
+        // generated by build.rs. Start and length are aligned to 8 bytes, or
+        // to 32 bytes if flags bit 0 says we can use the faster `stm` loop.
+
+        // r4 is the address of the current entry in the table, r12 the end
         ldr     r4, =__ecc_table_start__
-        // skip to block loop termination check
-        b       .Lecc_init_table_loop_check
-        // process a table entry
-    .Lecc_init_word_loop_start:
-        // r5 counts how many 64-bit words have been written
+        ldr     r12, =__ecc_table_end__
+        // zero the registers we store
+        mov     r2, #0
+        mov     r3, #0
         mov     r5, #0
-        b       .Lecc_init_word_loop_check
-    .Lecc_init_word_loop_inner:
-        // load start address into r2 (length is in bytes)
-        ldr     r2, [r4, #0]
-        // multiply word count by 8 to get byte count
-        lsls    r3, r5, #3
-        // calculate address to write as (start + current index)
-        adds    r1, r2, r3
-        ldr     r2, =0x00000000
-        ldr     r3, =0x00000000
-        // write out one word to address in r1
-        strd    r2, r3, [r1]
-        // increment
-        adds    r5, #1
-        // load section length in bytes
-        ldr     r3, [r4, #4]
-        // divide length by eight
-        lsrs    r2, r3, #3
-        // compare counter with length
-        cmp     r5, r2
-    .Lecc_init_word_loop_check:
-        // load the section length
-        ldr     r3, [r4, #4]
-        // divide section length by eight to get words
-        lsrs    r2, r3, #3
-        // have we written out enough words?
-        cmp     r5, r2
-        // if not, write some more words
-        bcc     .Lecc_init_word_loop_inner
-        // increment pointer to point at next table entry
-        adds    r4, #8
-    .Lecc_init_table_loop_check:
-        // are we at the end of the table? (r4 points to the current table entry)
-        ldr     r3, =__ecc_table_end__
-        cmp     r4, r3
-        // if not equal, go do some more
-        bcc     .Lecc_init_word_loop_start
+        mov     r6, #0
+        mov     r7, #0
+        mov     r8, #0
+        mov     r9, #0
+        mov     r10, #0
+    .Lecc_init_table_loop:
+        // are we at the end of the table?
+        cmp     r4, r12
+        bhs     .Lecc_init_done
+        // load (start, length, flags) into r0, r1, r11, and move on
+        ldm     r4!, {{r0, r1, r11}}
+        // r1 is now the end address
+        add     r1, r0, r1
+        tst     r11, #1
+        bne     .Lecc_init_fast_loop
+    .Lecc_init_slow_loop:
+        // write 8 bytes at a time
+        cmp     r0, r1
+        bhs     .Lecc_init_table_loop
+        strd    r2, r3, [r0], #8
+        b       .Lecc_init_slow_loop
+    .Lecc_init_fast_loop:
+        // write 32 bytes at a time
+        cmp     r0, r1
+        bhs     .Lecc_init_table_loop
+        stm     r0!, {{r2, r3, r5-r10}}
+        b       .Lecc_init_fast_loop
+    .Lecc_init_done:
+
+        bl      InitCore
+
+        // ECC init is now done. Drop to EL1, or start the EL2 monitor.
+        b       _s32z2_el2_start
+
+    .Lsecondary_start:
+        // r3 is our core ID. Wait for core 0 to release us, as it must
+        // initialise the ECC on the RAM we are about to use.
+        ldr     r1, =_core_release
+    .Lsecondary_wait:
+        ldr     r2, [r1]
+        cmp     r2, #0
+        bne     .Lsecondary_released
+        wfe
+        b       .Lsecondary_wait
+    .Lsecondary_released:
+        bl      InitCore
+
+        // Find our entry in the core table (which starts at core 1)
+        ldr     r4, =__core_table_start__
+        sub     r5, r3, #1
+        mov     r6, #24
+        mla     r4, r5, r6, r4
+        ldr     r5, =__core_table_end__
+        cmp     r4, r5
+        bhs     .Lsecondary_park
+
+        // Drop from EL2 to EL1 in SVC mode, as _default_start does
+        mrs     r0, cpsr
+        and     r0, r0, #0x1F
+        cmp     r0, #0x1A             /* Are we in Hyp mode? */
+        bne     .Lsecondary_el1
+        ldr     r0, =_vector_table
+        mcr     p15, 4, r0, c12, c0, 0 /* Write HVBAR */
+        mrc     p15, 4, r0, c1, c0, 1 /* Read HACTLR */
+        ldr     r1, =0xF83            /* EL1 access to CPUACTLR, CDBGDCI, PERIPHPREGIONR, QOSR, etc */
+        orr     r0, r0, r1
+        mcr     p15, 4, r0, c1, c0, 1 /* Write HACTLR */
+        mov     r0, #0xD3             /* SVC mode, Arm state, IRQ and FIQ masked */
+        msr     spsr_hyp, r0
+        adr     r0, .Lsecondary_el1
+        msr     elr_hyp, r0
+        dsb
+        isb
+        eret
+
+    .Lsecondary_el1:
+        // Set up a stack for each mode, working down from our stack top
+        ldr     r0, [r4, #0]
+        cps     #0x1B                 /* UND mode */
+        mov     sp, r0
+        ldr     r1, =_und_stack_size
+        sub     r0, r0, r1
+        cps     #0x13                 /* SVC mode */
+        mov     sp, r0
+        ldr     r1, =_svc_stack_size
+        sub     r0, r0, r1
+        cps     #0x17                 /* ABT mode */
+        mov     sp, r0
+        ldr     r1, =_abt_stack_size
+        sub     r0, r0, r1
+        cps     #0x12                 /* IRQ mode */
+        mov     sp, r0
+        ldr     r1, =_irq_stack_size
+        sub     r0, r0, r1
+        cps     #0x11                 /* FIQ mode */
+        mov     sp, r0
+        ldr     r1, =_fiq_stack_size
+        sub     r0, r0, r1
+        cps     #0x1F                 /* SYS mode */
+        mov     sp, r0
+
+        // Zero this core's .bss
+        ldr     r0, [r4, #4]
+        ldr     r1, [r4, #8]
+        mov     r2, #0
+    .Lsecondary_bss_loop:
+        cmp     r0, r1
+        strlo   r2, [r0], #4
+        blo     .Lsecondary_bss_loop
+
+        // Copy this core's .data from its load address
+        ldr     r0, [r4, #12]
+        ldr     r1, [r4, #16]
+        ldr     r2, [r4, #20]
+    .Lsecondary_data_loop:
+        cmp     r0, r1
+        ldrlo   r5, [r2], #4
+        strlo   r5, [r0], #4
+        blo     .Lsecondary_data_loop
+
+        // Allow access to the FPU
+        mrc     p15, 0, r0, c1, c0, 2 /* Read CPACR */
+        orr     r0, r0, #0xF00000     /* Full access to CP10 and CP11 */
+        mcr     p15, 0, r0, c1, c0, 2 /* Write CPACR */
+        isb
+        mov     r0, #0x40000000       /* FPEXC.EN */
+        vmsr    fpexc, r0
+
+        // Jump to Rust, passing our core ID
+        mov     r0, r3
+        bl      kmain_secondary
+
+    .Lsecondary_park:
+        wfi
+        b       .Lsecondary_park
+
+    // Per-core initialisation, run by every core. Preserves r3.
+    InitCore:
+        mov     r12, lr
 
         /* TCM initialization */
         ldr     r0, =__TCMA_Start     /* Load new BASE address*/
@@ -138,15 +412,14 @@ core::arch::global_asm!(
         bl      InitTcmLoop
        
         // Load Generic Timer frequency register before we leave EL2.
-        // We're on a 40 MHz crystal and experimentally we have determined the
-        // timer is running at 8 MHz, so there's probably a /5 divider. The
-        // default value for CNTDIV is 4, and a zero divider doesn't make sense,
-        // so that seems to stack up.
-        ldr     r0, =8000000
+        // The MPU is still off, so we can't read the clock tree - this is
+        // worked out at compile time from the crystal and the counter
+        // dividers, and checked against a measurement once we're up and
+        // running.
+        ldr     r0, ={counter_hz}
         mcr     p15, 0, r0, c14, c0, 0
 
-        // ECC init is now done
-        b       _default_start
+        bx      r12
 
     InitTcmLoop:
         stm     r0, {{r4-r11}}        /* Move 8 location once 4*8=32 bytes */
@@ -155,5 +428,6 @@ core::arch::global_asm!(
         cmp     r1, #0                /* Is the end of DMEM? */
         bne     InitTcmLoop           /* Restart loop if not */
         bx      lr
-    "#
+    "#,
+    counter_hz = const clocks::COUNTER_HZ,
 );
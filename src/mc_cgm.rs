@@ -0,0 +1,327 @@
+//! Clock Generation Module code for the S32Z2
+//!
+//! Each *MC_CGM* (Clock Generation Module) instance has a number of clock
+//! muxes, which select one of the PLL, DFS or oscillator outputs, and each mux
+//! feeds one or more dividers. These route the clocks from the [`crate::clocks`]
+//! module to the cores, buses and peripherals.
+
+use arbitrary_int::{u3, u6};
+
+use crate::clocks::poll;
+
+/// The MC_CGM instances on the S32Z2
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Instance {
+    /// MC_CGM_0
+    Cgm0,
+    /// MC_CGM_1
+    Cgm1,
+    /// MC_CGM_2
+    Cgm2,
+    /// MC_CGM_3
+    Cgm3,
+    /// MC_CGM_4
+    Cgm4,
+    /// MC_CGM_5
+    Cgm5,
+}
+
+impl Instance {
+    /// The base address of this MC_CGM instance
+    pub const fn base_address(self) -> usize {
+        match self {
+            Instance::Cgm0 => 0x4003_0000,
+            Instance::Cgm1 => 0x4083_0000,
+            Instance::Cgm2 => 0x4103_0000,
+            Instance::Cgm3 => 0x4183_0000,
+            Instance::Cgm4 => 0x4203_0000,
+            Instance::Cgm5 => 0x4283_0000,
+        }
+    }
+
+    /// The address of the given clock mux within this MC_CGM instance
+    pub const fn mux_address(self, index: usize) -> usize {
+        self.base_address() + MUX_OFFSET + (index * MUX_STRIDE)
+    }
+}
+
+/// The MC_CGM mux and divider that clock a peripheral
+///
+/// This crate has no table of these, as none has been checked against the
+/// *Clocking* chapter of the S32Z2 Reference Manual. Fill one in from the
+/// chapter for the peripheral you want to clock, and pass it to
+/// [`configure_peripheral`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PeripheralClock {
+    /// The MC_CGM instance with the mux
+    pub instance: Instance,
+    /// The mux, within `instance`
+    pub mux: usize,
+    /// The divider, on `mux`
+    pub divider: usize,
+}
+
+/// Offset from the MC_CGM base address to the registers for MUX_0
+const MUX_OFFSET: usize = 0x300;
+
+/// Distance between the registers for MUX_n and MUX_n+1
+const MUX_STRIDE: usize = 0x40;
+
+/// Errors that can occur when configuring a clock mux
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The mux did not finish switching in time
+    SwitchTimeout,
+    /// The mux switch finished, but did not select the requested source
+    SwitchFailed {
+        /// The switch trigger cause reported by the hardware
+        trigger: u3,
+        /// The source the mux actually has selected
+        selected: u6,
+    },
+    /// A divider update did not finish in time
+    DividerTimeout,
+    /// There is no such divider on this mux
+    NoSuchDivider,
+}
+
+/// Identifies a clock source, as selected by a clock mux
+///
+/// The source numbers differ for each mux - see the *Clock selector* tables
+/// in the Reference Manual.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ClockSource(u6);
+
+impl ClockSource {
+    /// The Fast Internal RC oscillator, which every mux can select
+    pub const FIRC: ClockSource = ClockSource(u6::new(0));
+
+    /// Create a clock source from a raw selector value
+    pub const fn new(selctl: u6) -> ClockSource {
+        ClockSource(selctl)
+    }
+
+    /// Get the raw selector value
+    pub const fn selctl(self) -> u6 {
+        self.0
+    }
+}
+
+/// One MC_CGM Clock Mux, and its dividers
+///
+/// Not every mux implements every divider, or the divider trigger registers.
+#[derive(derive_mmio::Mmio)]
+#[repr(C)]
+pub struct McCgmMux {
+    /// Clock Mux Select Control, offset: 0x0
+    csc: McCgmMuxCsc,
+    /// Clock Mux Select Status, offset: 0x4
+    css: McCgmMuxCss,
+    /// Clock Mux Divider Control, offset: 0x8
+    dc: [McCgmMuxDc; 11],
+    /// Clock Mux Divider Trigger Control, offset: 0x34
+    div_trig_ctrl: u32,
+    /// Clock Mux Divider Trigger, offset: 0x38
+    div_trig: u32,
+    /// Clock Mux Divider Update Status, offset: 0x3C
+    div_upd_stat: McCgmMuxDivUpdStat,
+}
+
+/// The Clock Mux Select Control Register
+#[bitbybit::bitfield(u32)]
+pub struct McCgmMuxCsc {
+    /// Which clock source to switch to
+    #[bits(24..=29, rw)]
+    selctl: u6,
+    /// Write true to switch to the safe clock (FIRC)
+    #[bit(3, rw)]
+    safe_sw: bool,
+    /// Write true to switch to the source in `selctl`
+    #[bit(2, rw)]
+    clk_sw: bool,
+}
+
+impl core::fmt::Debug for McCgmMuxCsc {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "McCgmMuxCsc(selctl={}, safe_sw={}, clk_sw={})",
+            self.selctl(),
+            self.safe_sw(),
+            self.clk_sw()
+        )
+    }
+}
+
+/// The Clock Mux Select Status Register
+#[bitbybit::bitfield(u32)]
+pub struct McCgmMuxCss {
+    /// Which clock source is currently selected
+    #[bits(24..=29, r)]
+    selstat: u6,
+    /// Why the last switch happened
+    ///
+    /// A value of [`SWTRG_SUCCEEDED`] means a requested switch succeeded.
+    #[bits(17..=19, r)]
+    swtrg: u3,
+    /// If true, a clock switch is in progress
+    #[bit(16, r)]
+    swip: bool,
+    /// If true, a switch to the safe clock was requested
+    #[bit(3, r)]
+    safe_sw: bool,
+    /// If true, the last requested clock switch has completed
+    #[bit(2, r)]
+    clk_sw: bool,
+}
+
+/// The `swtrg` value meaning "switch after request succeeded"
+pub const SWTRG_SUCCEEDED: u3 = u3::new(1);
+
+impl core::fmt::Debug for McCgmMuxCss {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "McCgmMuxCss(selstat={}, swtrg={}, swip={}, safe_sw={}, clk_sw={})",
+            self.selstat(),
+            self.swtrg(),
+            self.swip(),
+            self.safe_sw(),
+            self.clk_sw()
+        )
+    }
+}
+
+/// A Clock Mux Divider Control Register
+#[bitbybit::bitfield(u32)]
+pub struct McCgmMuxDc {
+    /// If true, the divider (and hence the output) is enabled
+    #[bit(31, rw)]
+    de: bool,
+    /// Division value, minus one
+    #[bits(16..=23, rw)]
+    div: u8,
+}
+
+impl core::fmt::Debug for McCgmMuxDc {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "McCgmMuxDc(de={}, div={})", self.de(), self.div())
+    }
+}
+
+/// The Clock Mux Divider Update Status Register
+#[bitbybit::bitfield(u32)]
+pub struct McCgmMuxDivUpdStat {
+    /// If true, a divider update is still in progress
+    #[bit(0, r)]
+    div_stat: bool,
+}
+
+impl core::fmt::Debug for McCgmMuxDivUpdStat {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "McCgmMuxDivUpdStat(div_stat={})", self.div_stat())
+    }
+}
+
+/// Get a handle to one of the clock muxes
+///
+/// # Safety
+///
+/// Only create one handle to any given mux at a time, and only for muxes
+/// that exist on this instance.
+pub unsafe fn mux(instance: Instance, index: usize) -> MmioMcCgmMux<'static> {
+    unsafe { McCgmMux::new_mmio_at(instance.mux_address(index)) }
+}
+
+/// Switch a clock mux to a new source
+///
+/// Waits for any switch already in progress, requests the new source, then
+/// waits for the switch to complete and checks it took effect. The new source
+/// must be running, otherwise the switch will fail.
+pub fn switch_mux(mux: &mut MmioMcCgmMux, source: ClockSource) -> Result<(), Error> {
+    if !poll(|| !mux.read_css().swip()) {
+        return Err(Error::SwitchTimeout);
+    }
+    mux.modify_csc(|w| w.with_selctl(source.selctl()).with_clk_sw(true));
+    if !poll(|| {
+        let css = mux.read_css();
+        css.clk_sw() && !css.swip()
+    }) {
+        return Err(Error::SwitchTimeout);
+    }
+    let css = mux.read_css();
+    if css.swtrg() != SWTRG_SUCCEEDED || css.selstat() != source.selctl() {
+        return Err(Error::SwitchFailed {
+            trigger: css.swtrg(),
+            selected: css.selstat(),
+        });
+    }
+    Ok(())
+}
+
+/// Which source a clock mux has selected
+pub fn selected(mux: &mut MmioMcCgmMux) -> ClockSource {
+    ClockSource(mux.read_css().selstat())
+}
+
+/// Program one of the dividers on a clock mux
+///
+/// The `div` value is as written to the register, so the mux output is
+/// divided by `div + 1`. Passing `None` disables the divider, stopping its
+/// output.
+pub fn set_divider(mux: &mut MmioMcCgmMux, divider: usize, div: Option<u8>) -> Result<(), Error> {
+    if !poll(|| !mux.read_div_upd_stat().div_stat()) {
+        return Err(Error::DividerTimeout);
+    }
+    let value = match div {
+        Some(div) => McCgmMuxDc::new_with_raw_value(0)
+            .with_div(div)
+            .with_de(true),
+        None => McCgmMuxDc::new_with_raw_value(0),
+    };
+    mux.write_dc(divider, value)
+        .map_err(|_| Error::NoSuchDivider)?;
+    if !poll(|| !mux.read_div_upd_stat().div_stat()) {
+        return Err(Error::DividerTimeout);
+    }
+    Ok(())
+}
+
+/// Clock a peripheral from the given source, through the given divider
+///
+/// Switches the peripheral's mux, then programs its divider. Uses the same
+/// `div` encoding as [`set_divider`].
+///
+/// # Safety
+///
+/// `clock` must name a mux and divider that exist. Every peripheral on that
+/// mux sees its clock change, so none of them may be in use. No other handle
+/// to that mux may exist.
+pub unsafe fn configure_peripheral(
+    clock: PeripheralClock,
+    source: ClockSource,
+    div: Option<u8>,
+) -> Result<(), Error> {
+    let mut mux = unsafe { mux(clock.instance, clock.mux) };
+    switch_mux(&mut mux, source)?;
+    set_divider(&mut mux, clock.divider, div)
+}
+
+/// Read back one of the dividers on a clock mux
+///
+/// Uses the same `div` encoding as [`set_divider`], so `None` means the
+/// divider is disabled.
+pub fn divider(mux: &mut MmioMcCgmMux, divider: usize) -> Result<Option<u8>, Error> {
+    let dc = mux.read_dc(divider).map_err(|_| Error::NoSuchDivider)?;
+    Ok(dc.de().then_some(dc.div()))
+}
+
+/// Calculate the output frequency of a divider, in Hz
+///
+/// Uses the same `div` encoding as [`set_divider`].
+pub const fn divided_hz(source_hz: u32, div: Option<u8>) -> u32 {
+    match div {
+        Some(div) => source_hz / (div as u32 + 1),
+        None => 0,
+    }
+}
@@ -0,0 +1,417 @@
+//! Mode Entry Module code for the S32Z2
+//!
+//! The *MC_ME* (Mode Entry Module) controls the clocks to each partition of
+//! the SoC, and to each core within a partition. Changes only take effect
+//! after the magic key sequence is written to the `CTL_KEY` register.
+//!
+//! This lets firmware on one core start the other cores, as the TRACE32
+//! script `s32z27_connect_r52_rtu0.cmm` does.
+
+use arbitrary_int::{u3, u5};
+
+use crate::clocks::poll;
+use crate::mc_rgm::{self, MmioMcRgm};
+
+/// Base address of the MC_ME
+const MC_ME_BASE: usize = 0x4190_0000;
+
+/// Offset from the MC_ME base address to the registers for PRTN0
+const PARTITION_OFFSET: usize = 0x100;
+
+/// Distance between the registers for PRTNn and PRTNn+1
+const PARTITION_STRIDE: usize = 0x200;
+
+/// Offset from a partition's registers to the registers for its CORE0
+const CORE_OFFSET: usize = 0x40;
+
+/// Distance between the registers for COREn and COREn+1
+const CORE_STRIDE: usize = 0x20;
+
+/// The first value written to `CTL_KEY` to apply changes
+const CTL_KEY: u32 = 0x5AF0;
+
+/// The second value written to `CTL_KEY` to apply changes
+const CTL_KEY_INVERTED: u32 = 0xA50F;
+
+/// Errors that can occur when changing partition or core state
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The partition did not change state in time
+    PartitionTimeout,
+    /// The core clock did not change state in time
+    CoreTimeout,
+    /// The core entry address must be 4-byte aligned
+    UnalignedAddress,
+    /// The core could not be released from reset
+    Reset(mc_rgm::Error),
+}
+
+impl From<mc_rgm::Error> for Error {
+    fn from(value: mc_rgm::Error) -> Self {
+        Error::Reset(value)
+    }
+}
+
+/// The partitions containing Cortex-R52 cores
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Partition {
+    /// Real-Time Unit 0 (Partition 1)
+    Rtu0,
+    /// Real-Time Unit 1 (Partition 2)
+    Rtu1,
+}
+
+impl Partition {
+    /// The MC_ME partition number
+    pub const fn index(self) -> usize {
+        match self {
+            Partition::Rtu0 => 1,
+            Partition::Rtu1 => 2,
+        }
+    }
+
+    /// The address of this partition's registers
+    const fn address(self) -> usize {
+        MC_ME_BASE + PARTITION_OFFSET + (self.index() * PARTITION_STRIDE)
+    }
+}
+
+/// The Cortex-R52 cores within a Real-Time Unit
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Core {
+    /// Core 0
+    Core0,
+    /// Core 1
+    Core1,
+    /// Core 2
+    Core2,
+    /// Core 3
+    Core3,
+}
+
+impl Core {
+    /// The MC_ME core number within the partition
+    pub const fn index(self) -> usize {
+        match self {
+            Core::Core0 => 0,
+            Core::Core1 => 1,
+            Core::Core2 => 2,
+            Core::Core3 => 3,
+        }
+    }
+
+    /// The address of this core's registers in the given partition
+    const fn address(self, partition: Partition) -> usize {
+        partition.address() + CORE_OFFSET + (self.index() * CORE_STRIDE)
+    }
+}
+
+/// The MC_ME top-level registers
+#[derive(derive_mmio::Mmio)]
+#[repr(C)]
+pub struct McMe {
+    /// Control Key, offset: 0x0
+    ctl_key: u32,
+    /// Mode Configuration, offset: 0x4
+    mode_conf: McMeModeConf,
+    /// Mode Update, offset: 0x8
+    mode_upd: McMeModeUpd,
+    /// Mode Status, offset: 0xC
+    mode_stat: u32,
+    /// Main Core ID, offset: 0x10
+    main_coreid: McMeMainCoreId,
+}
+
+/// The Mode Configuration Register
+#[bitbybit::bitfield(u32)]
+pub struct McMeModeConf {
+    /// Request a functional reset
+    #[bit(1, rw)]
+    func_rst: bool,
+    /// Request a destructive reset
+    #[bit(0, rw)]
+    dest_rst: bool,
+}
+
+impl core::fmt::Debug for McMeModeConf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "McMeModeConf(func_rst={}, dest_rst={})",
+            self.func_rst(),
+            self.dest_rst()
+        )
+    }
+}
+
+/// The Mode Update Register
+#[bitbybit::bitfield(u32)]
+pub struct McMeModeUpd {
+    /// Write true to apply the `mode_conf` register on the next key sequence
+    #[bit(0, rw)]
+    mode_upd: bool,
+}
+
+impl core::fmt::Debug for McMeModeUpd {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "McMeModeUpd(mode_upd={})", self.mode_upd())
+    }
+}
+
+/// The Main Core ID Register
+#[bitbybit::bitfield(u32)]
+pub struct McMeMainCoreId {
+    /// The partition containing the main core
+    #[bits(8..=12, r)]
+    pidx: u5,
+    /// The index of the main core within its partition
+    #[bits(0..=2, r)]
+    cidx: u3,
+}
+
+impl core::fmt::Debug for McMeMainCoreId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "McMeMainCoreId(pidx={}, cidx={})",
+            self.pidx(),
+            self.cidx()
+        )
+    }
+}
+
+/// The MC_ME registers for one partition
+#[derive(derive_mmio::Mmio)]
+#[repr(C)]
+pub struct McMePartition {
+    /// Partition Process Configuration, offset: 0x0
+    pconf: McMePrtnPconf,
+    /// Partition Process Update, offset: 0x4
+    pupd: McMePrtnPupd,
+    /// Partition Status, offset: 0x8
+    stat: McMePrtnStat,
+    _reserved0: u32,
+    /// Partition COFB Clock Status, offset: 0x10
+    cofb_stat: [u32; 4],
+    _reserved1: [u32; 4],
+    /// Partition COFB Clock Enable, offset: 0x30
+    cofb_clken: [u32; 4],
+}
+
+/// The Partition Process Configuration Register
+#[bitbybit::bitfield(u32)]
+pub struct McMePrtnPconf {
+    /// Output Safe Stating Enable - isolates the partition outputs
+    #[bit(2, rw)]
+    osse: bool,
+    /// Partition Clock Enable
+    #[bit(0, rw)]
+    pce: bool,
+}
+
+impl core::fmt::Debug for McMePrtnPconf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "McMePrtnPconf(osse={}, pce={})", self.osse(), self.pce())
+    }
+}
+
+/// The Partition Process Update Register
+#[bitbybit::bitfield(u32)]
+pub struct McMePrtnPupd {
+    /// Apply `osse` on the next key sequence
+    #[bit(2, rw)]
+    ossud: bool,
+    /// Apply `pce` on the next key sequence
+    #[bit(0, rw)]
+    pcud: bool,
+}
+
+impl core::fmt::Debug for McMePrtnPupd {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "McMePrtnPupd(ossud={}, pcud={})",
+            self.ossud(),
+            self.pcud()
+        )
+    }
+}
+
+/// The Partition Status Register
+#[bitbybit::bitfield(u32)]
+pub struct McMePrtnStat {
+    /// If true, the partition outputs are safe-stated
+    #[bit(2, r)]
+    osss: bool,
+    /// If true, the partition clock is running
+    #[bit(0, r)]
+    pcs: bool,
+}
+
+impl core::fmt::Debug for McMePrtnStat {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "McMePrtnStat(osss={}, pcs={})", self.osss(), self.pcs())
+    }
+}
+
+/// The MC_ME registers for one core within a partition
+#[derive(derive_mmio::Mmio)]
+#[repr(C)]
+pub struct McMeCore {
+    /// Core Process Configuration, offset: 0x0
+    pconf: McMeCorePconf,
+    /// Core Process Update, offset: 0x4
+    pupd: McMeCorePupd,
+    /// Core Status, offset: 0x8
+    stat: McMeCoreStat,
+    /// Core Address, offset: 0xC
+    addr: u32,
+}
+
+/// The Core Process Configuration Register
+#[bitbybit::bitfield(u32)]
+pub struct McMeCorePconf {
+    /// Core Clock Enable
+    #[bit(0, rw)]
+    cce: bool,
+}
+
+impl core::fmt::Debug for McMeCorePconf {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "McMeCorePconf(cce={})", self.cce())
+    }
+}
+
+/// The Core Process Update Register
+#[bitbybit::bitfield(u32)]
+pub struct McMeCorePupd {
+    /// Apply `cce` on the next key sequence
+    #[bit(0, rw)]
+    ccupd: bool,
+}
+
+impl core::fmt::Debug for McMeCorePupd {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "McMeCorePupd(ccupd={})", self.ccupd())
+    }
+}
+
+/// The Core Status Register
+#[bitbybit::bitfield(u32)]
+pub struct McMeCoreStat {
+    /// If true, the core is in Wait For Interrupt
+    #[bit(31, r)]
+    wfi: bool,
+    /// If true, the core clock is running
+    #[bit(0, r)]
+    ccs: bool,
+}
+
+impl core::fmt::Debug for McMeCoreStat {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "McMeCoreStat(wfi={}, ccs={})", self.wfi(), self.ccs())
+    }
+}
+
+/// Get a handle to the MC_ME
+///
+/// # Safety
+///
+/// Only create one handle at a time. All the functions in this module assume
+/// that holding a `MmioMcMe` gives exclusive access to every MC_ME register.
+pub unsafe fn mc_me() -> MmioMcMe<'static> {
+    unsafe { McMe::new_mmio_at(MC_ME_BASE) }
+}
+
+/// Apply any pending configuration changes by writing the key sequence
+pub fn write_key(mc_me: &mut MmioMcMe) {
+    mc_me.write_ctl_key(CTL_KEY);
+    mc_me.write_ctl_key(CTL_KEY_INVERTED);
+}
+
+/// Get the partition registers for a partition
+fn partition_regs(_mc_me: &mut MmioMcMe, partition: Partition) -> MmioMcMePartition<'static> {
+    // SAFETY: The caller has exclusive access to the MC_ME
+    unsafe { McMePartition::new_mmio_at(partition.address()) }
+}
+
+/// Get the core registers for a core
+fn core_regs(_mc_me: &mut MmioMcMe, partition: Partition, core: Core) -> MmioMcMeCore<'static> {
+    // SAFETY: The caller has exclusive access to the MC_ME
+    unsafe { McMeCore::new_mmio_at(core.address(partition)) }
+}
+
+/// Enable the clock to a partition and take its outputs out of safe-state
+///
+/// Does nothing if the partition clock is already running. This only covers
+/// the MC_ME - the partition's fencing logic and reset domain controller are
+/// left as the boot ROM configured them.
+pub fn enable_partition(mc_me: &mut MmioMcMe, partition: Partition) -> Result<(), Error> {
+    let mut prtn = partition_regs(mc_me, partition);
+    if prtn.read_stat().pcs() && !prtn.read_stat().osss() {
+        return Ok(());
+    }
+
+    // Turn on the clock, keeping the outputs safe-stated
+    prtn.write_pconf(
+        McMePrtnPconf::new_with_raw_value(0)
+            .with_pce(true)
+            .with_osse(true),
+    );
+    prtn.write_pupd(McMePrtnPupd::new_with_raw_value(0).with_pcud(true));
+    write_key(mc_me);
+    if !poll(|| prtn.read_stat().pcs()) {
+        return Err(Error::PartitionTimeout);
+    }
+
+    // Now release the outputs
+    prtn.write_pconf(McMePrtnPconf::new_with_raw_value(0).with_pce(true));
+    prtn.write_pupd(McMePrtnPupd::new_with_raw_value(0).with_ossud(true));
+    write_key(mc_me);
+    if !poll(|| !prtn.read_stat().osss()) {
+        return Err(Error::PartitionTimeout);
+    }
+
+    Ok(())
+}
+
+/// Is the clock to this core running?
+pub fn core_running(mc_me: &mut MmioMcMe, partition: Partition, core: Core) -> bool {
+    core_regs(mc_me, partition, core).read_stat().ccs()
+}
+
+/// Start a core running from the given address
+///
+/// The address is where the core fetches its vector table from on reset, so
+/// it should point at a `_vector_table`. This sets the address, enables the
+/// core clock and then releases the core from reset.
+///
+/// # Safety
+///
+/// The given core must not currently be running, and the given address must
+/// hold valid code for it to run.
+pub unsafe fn start_core(
+    mc_me: &mut MmioMcMe,
+    rgm: &mut MmioMcRgm,
+    partition: Partition,
+    core: Core,
+    entry: usize,
+) -> Result<(), Error> {
+    if entry % 4 != 0 {
+        return Err(Error::UnalignedAddress);
+    }
+    enable_partition(mc_me, partition)?;
+
+    let mut regs = core_regs(mc_me, partition, core);
+    regs.write_addr(entry as u32);
+    regs.write_pconf(McMeCorePconf::new_with_raw_value(0).with_cce(true));
+    regs.write_pupd(McMeCorePupd::new_with_raw_value(0).with_ccupd(true));
+    write_key(mc_me);
+    if !poll(|| regs.read_stat().ccs()) {
+        return Err(Error::CoreTimeout);
+    }
+
+    mc_rgm::set_core_reset(rgm, partition, core, false)?;
+
+    Ok(())
+}
@@ -0,0 +1,351 @@
+//! Reset Generation Module code for the S32Z2
+//!
+//! The *MC_RGM* (Reset Generation Module) records what caused the last reset,
+//! and holds each partition, core and peripheral in (or out of) reset.
+
+use crate::clocks::poll;
+use crate::mc_me::{self, Core, MmioMcMe, Partition};
+
+/// Base address of the MC_RGM
+const MC_RGM_BASE: usize = 0x4185_0000;
+
+/// Errors that can occur when changing reset state
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The reset status did not follow the reset request in time
+    Timeout,
+    /// There is no such partition
+    NoSuchPartition,
+    /// There is no such bit in a 32-bit reset register
+    NoSuchBit,
+}
+
+/// The MC_RGM registers
+#[derive(derive_mmio::Mmio)]
+#[repr(C)]
+pub struct McRgm {
+    /// Destructive Event Status, offset: 0x0
+    des: McRgmDes,
+    _reserved0: u32,
+    /// Functional Event Status, offset: 0x8
+    fes: McRgmFes,
+    /// Functional Event Reset Disable, offset: 0xC
+    ferd: u32,
+    /// Functional Bidirectional Reset Enable, offset: 0x10
+    fbre: u32,
+    /// Functional Reset Escalation Counter, offset: 0x14
+    frec: u32,
+    /// Functional Reset Escalation Threshold, offset: 0x18
+    fret: u32,
+    /// Destructive Reset Escalation Threshold, offset: 0x1C
+    dret: u32,
+    _reserved1: [u32; 8],
+    /// Partition 0 Reset, offset: 0x40
+    prst0_0: u32,
+    _reserved2: u32,
+    /// Partition 1 Reset, offset: 0x48
+    prst1_0: u32,
+    _reserved3: u32,
+    /// Partition 2 Reset, offset: 0x50
+    prst2_0: u32,
+    _reserved4: u32,
+    /// Partition 3 Reset, offset: 0x58
+    prst3_0: u32,
+    _reserved5: [u32; 57],
+    /// Partition 0 Reset Status, offset: 0x140
+    pstat0_0: u32,
+    _reserved6: u32,
+    /// Partition 1 Reset Status, offset: 0x148
+    pstat1_0: u32,
+    _reserved7: u32,
+    /// Partition 2 Reset Status, offset: 0x150
+    pstat2_0: u32,
+    _reserved8: u32,
+    /// Partition 3 Reset Status, offset: 0x158
+    pstat3_0: u32,
+}
+
+/// The Destructive Event Status Register
+///
+/// Bits are set by hardware and cleared by writing one to them.
+#[bitbybit::bitfield(u32)]
+#[derive(PartialEq, Eq)]
+pub struct McRgmDes {
+    /// Reset requested by the debugger
+    #[bit(30, rw)]
+    debug_dest: bool,
+    /// Reset requested by software
+    #[bit(29, rw)]
+    sw_dest: bool,
+    /// Core clock failure
+    #[bit(10, rw)]
+    core_clk_fail: bool,
+    /// PLL loss of lock
+    #[bit(9, rw)]
+    pll_lol: bool,
+    /// Crystal oscillator failure
+    #[bit(8, rw)]
+    fxosc_fail: bool,
+    /// Functional reset escalation
+    #[bit(6, rw)]
+    mc_rgm_fre: bool,
+    /// Self-test unrecoverable failure
+    #[bit(4, rw)]
+    stcu_urf: bool,
+    /// FCCU failure to react
+    #[bit(3, rw)]
+    fccu_ftr: bool,
+    /// Power-on reset
+    #[bit(0, rw)]
+    f_por: bool,
+}
+
+impl core::fmt::Debug for McRgmDes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "McRgmDes({:#010x})", self.raw_value())
+    }
+}
+
+/// The Functional Event Status Register
+///
+/// Bits are set by hardware and cleared by writing one to them.
+#[bitbybit::bitfield(u32)]
+#[derive(PartialEq, Eq)]
+pub struct McRgmFes {
+    /// Reset requested by the debugger
+    #[bit(30, rw)]
+    debug_func: bool,
+    /// Reset requested by software
+    #[bit(29, rw)]
+    sw_func: bool,
+    /// Software Watchdog 3 timeout
+    #[bit(10, rw)]
+    swt3_rst: bool,
+    /// JTAG reset
+    #[bit(9, rw)]
+    jtag_rst: bool,
+    /// Software Watchdog 2 timeout
+    #[bit(8, rw)]
+    swt2_rst: bool,
+    /// Software Watchdog 1 timeout
+    #[bit(7, rw)]
+    swt1_rst: bool,
+    /// Software Watchdog 0 timeout
+    #[bit(6, rw)]
+    swt0_rst: bool,
+    /// Self-test done
+    #[bit(4, rw)]
+    st_done: bool,
+    /// FCCU reaction
+    #[bit(3, rw)]
+    fccu_rst: bool,
+    /// External reset pin
+    #[bit(0, rw)]
+    f_exr: bool,
+}
+
+impl core::fmt::Debug for McRgmFes {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "McRgmFes({:#010x})", self.raw_value())
+    }
+}
+
+/// Why the SoC last reset
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResetReason {
+    /// Power was applied
+    PowerOn,
+    /// The FCCU (Fault Collection and Control Unit) reacted to a fault
+    Fccu,
+    /// A Software Watchdog timed out
+    Watchdog {
+        /// Which SWT instance
+        index: u8,
+    },
+    /// A clock or PLL failed
+    ClockFailure,
+    /// Software asked for a reset
+    Software,
+    /// The debugger asked for a reset
+    Debugger,
+    /// The external reset pin was asserted
+    External,
+    /// Too many functional resets escalated to a destructive one
+    Escalation,
+    /// Something else, or nothing recorded
+    Unknown,
+}
+
+/// The kinds of reset software can request
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ResetKind {
+    /// Resets everything except the debug logic and some status registers
+    Functional,
+    /// Resets everything, as if the power was cycled
+    Destructive,
+}
+
+/// The recorded reset events
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ResetStatus {
+    /// Destructive reset events
+    pub des: McRgmDes,
+    /// Functional reset events
+    pub fes: McRgmFes,
+}
+
+impl ResetStatus {
+    /// Work out the most significant reason for the reset
+    ///
+    /// Destructive events take priority over functional ones, as a
+    /// destructive reset also clears the functional events.
+    pub fn reason(&self) -> ResetReason {
+        let des = self.des;
+        let fes = self.fes;
+        if des.fccu_ftr() {
+            ResetReason::Fccu
+        } else if des.fxosc_fail() || des.pll_lol() || des.core_clk_fail() {
+            ResetReason::ClockFailure
+        } else if des.mc_rgm_fre() {
+            ResetReason::Escalation
+        } else if des.sw_dest() {
+            ResetReason::Software
+        } else if des.debug_dest() {
+            ResetReason::Debugger
+        } else if des.f_por() {
+            ResetReason::PowerOn
+        } else if fes.fccu_rst() {
+            ResetReason::Fccu
+        } else if fes.swt0_rst() {
+            ResetReason::Watchdog { index: 0 }
+        } else if fes.swt1_rst() {
+            ResetReason::Watchdog { index: 1 }
+        } else if fes.swt2_rst() {
+            ResetReason::Watchdog { index: 2 }
+        } else if fes.swt3_rst() {
+            ResetReason::Watchdog { index: 3 }
+        } else if fes.sw_func() {
+            ResetReason::Software
+        } else if fes.debug_func() || fes.jtag_rst() {
+            ResetReason::Debugger
+        } else if fes.f_exr() {
+            ResetReason::External
+        } else {
+            ResetReason::Unknown
+        }
+    }
+}
+
+/// Get a handle to the MC_RGM
+///
+/// # Safety
+///
+/// Only create one handle at a time.
+pub unsafe fn mc_rgm() -> MmioMcRgm<'static> {
+    unsafe { McRgm::new_mmio_at(MC_RGM_BASE) }
+}
+
+/// Read the recorded reset events
+pub fn reset_status(rgm: &mut MmioMcRgm) -> ResetStatus {
+    ResetStatus {
+        des: rgm.read_des(),
+        fes: rgm.read_fes(),
+    }
+}
+
+/// Clear the given reset events, so the next reset is reported correctly
+pub fn clear_reset_status(rgm: &mut MmioMcRgm, status: &ResetStatus) {
+    rgm.write_des(status.des);
+    rgm.write_fes(status.fes);
+}
+
+/// Read the reset control bits for a partition
+fn read_prst(rgm: &mut MmioMcRgm, partition: usize) -> Result<u32, Error> {
+    match partition {
+        0 => Ok(rgm.read_prst0_0()),
+        1 => Ok(rgm.read_prst1_0()),
+        2 => Ok(rgm.read_prst2_0()),
+        3 => Ok(rgm.read_prst3_0()),
+        _ => Err(Error::NoSuchPartition),
+    }
+}
+
+/// Write the reset control bits for a partition
+fn write_prst(rgm: &mut MmioMcRgm, partition: usize, value: u32) -> Result<(), Error> {
+    match partition {
+        0 => rgm.write_prst0_0(value),
+        1 => rgm.write_prst1_0(value),
+        2 => rgm.write_prst2_0(value),
+        3 => rgm.write_prst3_0(value),
+        _ => return Err(Error::NoSuchPartition),
+    }
+    Ok(())
+}
+
+/// Read the reset status bits for a partition
+fn read_pstat(rgm: &mut MmioMcRgm, partition: usize) -> Result<u32, Error> {
+    match partition {
+        0 => Ok(rgm.read_pstat0_0()),
+        1 => Ok(rgm.read_pstat1_0()),
+        2 => Ok(rgm.read_pstat2_0()),
+        3 => Ok(rgm.read_pstat3_0()),
+        _ => Err(Error::NoSuchPartition),
+    }
+}
+
+/// Assert or release one of the resets in a partition
+///
+/// The `bit` is the bit number in that partition's `PRSTn_0` register. Waits
+/// for the reset status to match the request. Returns
+/// [`Error::NoSuchBit`] if `bit` is more than 31.
+pub fn set_reset(
+    rgm: &mut MmioMcRgm,
+    partition: usize,
+    bit: u32,
+    asserted: bool,
+) -> Result<(), Error> {
+    let mask = 1u32.checked_shl(bit).ok_or(Error::NoSuchBit)?;
+    let value = read_prst(rgm, partition)?;
+    let value = if asserted {
+        value | mask
+    } else {
+        value & !mask
+    };
+    write_prst(rgm, partition, value)?;
+    let mut status_ok =
+        || read_pstat(rgm, partition).is_ok_and(|stat| ((stat & mask) != 0) == asserted);
+    if !poll(&mut status_ok) {
+        return Err(Error::Timeout);
+    }
+    Ok(())
+}
+
+/// Assert or release the reset for a core
+///
+/// Bit 0 of a partition's reset register is the partition itself, then there
+/// is one bit per core.
+pub fn set_core_reset(
+    rgm: &mut MmioMcRgm,
+    partition: Partition,
+    core: Core,
+    asserted: bool,
+) -> Result<(), Error> {
+    set_reset(rgm, partition.index(), core.index() as u32 + 1, asserted)
+}
+
+/// Reset the SoC
+///
+/// The request goes through the MC_ME, and takes effect after the key
+/// sequence is written.
+pub fn software_reset(mc_me: &mut MmioMcMe, kind: ResetKind) -> ! {
+    mc_me.write_mode_conf(
+        mc_me::McMeModeConf::new_with_raw_value(0)
+            .with_func_rst(kind == ResetKind::Functional)
+            .with_dest_rst(kind == ResetKind::Destructive),
+    );
+    mc_me.write_mode_upd(mc_me::McMeModeUpd::new_with_raw_value(0).with_mode_upd(true));
+    mc_me::write_key(mc_me);
+    loop {
+        cortex_ar::asm::wfi();
+    }
+}
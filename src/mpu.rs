@@ -3,6 +3,41 @@
 //! The configuration of the MPU on this chip is *mandatory*. You cannot access
 //! any peripherals using the default MPU 'background' configuration that
 //! applies when the MPU is disabled.
+//!
+//! Each core gets its own map, so that it can have guard regions below its
+//! own stacks. Build with the `debug-breakpoints` feature to make `.text`
+//! writable, so the debugger can set soft breakpoints. EL0 can only read and
+//! execute Code RAM - everything else is `ReadWriteNoEL0`, apart from the
+//! task RAM while an EL0 task is running.
+//!
+//! The fixed map uses at most [`NUM_REGIONS`] regions (one fewer on cores
+//! without task RAM). The other cores' Data RAM shares a region with our own
+//! statics and stacks, and the peripherals and GIC share one Device region,
+//! so that a Cortex-R52 with 16 EL1 MPU regions has at least three spare.
+//! Any MPU regions not used by that map are spare. Applications can
+//! [`reserve`] them at runtime, to map memory the fixed map doesn't cover,
+//! such as a peripheral outside the RTU0 peripheral range:
+//!
+//! ```rust,ignore
+//! let mut region = mpu::reserve()?;
+//! region.set(El1Region {
+//!     range: window_start..=window_end,
+//!     shareability: El1Shareability::NonShareable,
+//!     access: El1AccessPerms::ReadWriteNoEL0,
+//!     no_exec: true,
+//!     mair: mpu::MPU_MAIR_INDEX_DEVICE,
+//!     enable: true,
+//! })?;
+//! ```
+//!
+//! Enabled regions must not overlap, so this can't change the attributes of
+//! RAM the fixed map already covers. A DMA buffer that needs to be
+//! non-cacheable can go in `.shared`, which already is.
+
+use core::{
+    marker::PhantomData,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
 use cortex_ar::{
     self as _,
@@ -12,99 +47,336 @@ use cortex_ar::{
     },
 };
 
+use crate::stacks::StackLayout;
+
+// The RAM ranges, generated by build.rs from the same layout as memory.x
+include!(concat!(env!("OUT_DIR"), "/memory_map.rs"));
+
+/// The RTU0 P0 Peripherals (from 0x4000_0000), the peripherals on bridges P1
+/// to P5 (MC_CGM, MC_ME, MC_RGM, SMU MRU, etc, from 0x4080_0000), and the RTU0
+/// GICv3 (from 0x4780_0000), which sit next to each other
+pub(crate) const PERIPHERAL_RANGE: core::ops::RangeInclusive<usize> = 0x4000_0000..=0x479F_FFFF;
+
 /// Enable extra debug output over DCC
 static VERBOSE_DEBUGGING: bool = false;
 
 /// Index of MAIR Attr used for code regions
-const MPU_MAIR_INDEX_CODE: u8 = 0;
+pub const MPU_MAIR_INDEX_CODE: u8 = 0;
 
 /// Index of MAIR Attr used for data regions
-const MPU_MAIR_INDEX_DATA: u8 = 1;
+pub const MPU_MAIR_INDEX_DATA: u8 = 1;
 
 /// Index of MAIR Attr used for peripheral regions
-const MPU_MAIR_INDEX_DEVICE: u8 = 2;
+pub const MPU_MAIR_INDEX_DEVICE: u8 = 2;
+
+/// Index of MAIR Attr used for data shared between cores
+pub const MPU_MAIR_INDEX_SHARED: u8 = 3;
+
+/// How many MPU regions the fixed map has
+///
+/// Only core 0, which has task RAM, enables all of them.
+pub const NUM_REGIONS: usize = 13;
+
+/// How many regions the fixed map must leave spare, on an MPU with 16
+const MIN_SPARE_REGIONS: usize = 3;
+
+const _: () = assert!(NUM_REGIONS + MIN_SPARE_REGIONS <= 16);
+
+/// PMSAv8 regions start and end on a 64-byte boundary
+const REGION_ALIGNMENT: usize = 64;
+
+/// Errors that can occur when programming the MPU
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A region does not start and end on a 64-byte boundary
+    Unaligned,
+    /// A region overlaps an existing region, which would make accesses to the
+    /// overlap fault
+    Overlap {
+        /// The index of the existing region
+        region: u8,
+    },
+    /// Every MPU region is in use
+    NoFreeRegions,
+    /// The MPU rejected the configuration
+    Mpu,
+    /// This core has no task RAM
+    NoTaskRam,
+}
+
+/// The first spare MPU region on each core, after the fixed map
+///
+/// Each core only touches its own entry, but one image runs on every core, so
+/// this has to live somewhere they can all see. Lives in `.shared` because
+/// that is not cached, and the cores' caches are not coherent.
+#[link_section = ".shared"]
+static FIRST_SPARE: [AtomicU32; crate::gic::NUM_CORES] =
+    [const { AtomicU32::new(0) }; crate::gic::NUM_CORES];
+
+/// Which spare MPU regions have been reserved, on each core (bit N is region N)
+#[link_section = ".shared"]
+static RESERVED: [AtomicU32; crate::gic::NUM_CORES] =
+    [const { AtomicU32::new(0) }; crate::gic::NUM_CORES];
+
+/// Size of the no-access guard region at the bottom of each stack
+const STACK_GUARD_SIZE: usize = 64;
+
+/// Memory attributes, indexed by the `MPU_MAIR_INDEX_*` constants
+pub(crate) static MEMORY_ATTRIBUTES: [MemAttr; 4] = [
+    // MPU_MAIR_INDEX_CODE
+    MemAttr::NormalMemory {
+        outer: Cacheable::WriteThroughNonTransient(RwAllocPolicy::R),
+        inner: Cacheable::WriteThroughNonTransient(RwAllocPolicy::R),
+    },
+    // MPU_MAIR_INDEX_DATA
+    MemAttr::NormalMemory {
+        outer: Cacheable::WriteBackNonTransient(RwAllocPolicy::R),
+        inner: Cacheable::WriteBackNonTransient(RwAllocPolicy::R),
+    },
+    // MPU_MAIR_INDEX_DEVICE
+    MemAttr::DeviceMemory,
+    // MPU_MAIR_INDEX_SHARED
+    MemAttr::NormalMemory {
+        outer: Cacheable::NonCacheable,
+        inner: Cacheable::NonCacheable,
+    },
+];
+
+/// Access permissions for `.text`
+///
+/// Ordinarily you'd want this read-only, except the debugger replaces
+/// instructions on-the-fly with soft breakpoints, so it has to be read-write
+/// if you want single-step debugging to work.
+const TEXT_ACCESS: El1AccessPerms = if cfg!(feature = "debug-breakpoints") {
+    El1AccessPerms::ReadWrite
+} else {
+    El1AccessPerms::ReadOnly
+};
+
+extern "C" {
+    static _stack_top: u8;
+    static __core0_hyp_stack_size: u8;
+    static __core1_stack_top: u8;
+    static __core2_stack_top: u8;
+    static __core3_stack_top: u8;
+    static _und_stack_size: u8;
+    static _svc_stack_size: u8;
+    static _abt_stack_size: u8;
+    static _irq_stack_size: u8;
+    static _fiq_stack_size: u8;
+    static _sys_stack_size: u8;
+    static __core_release_start: u8;
+    static __core_release_end: u8;
+    static __task_start: u8;
+}
+
+/// Where the start-up code put this core's stacks
+///
+/// Only core 0 can have a HYP stack, which `_default_start` leaves at the top
+/// of its Data RAM. Under the `hypervisor` feature the guest starts below EL2
+/// and has none - build.rs works out which.
+fn stack_layout(core: usize) -> StackLayout {
+    let addr = |sym: &u8| core::ptr::from_ref(sym) as usize;
+    // SAFETY: we only take the addresses of these linker symbols
+    unsafe {
+        let (top, hyp_size) = match core {
+            1 => (addr(&__core1_stack_top), 0),
+            2 => (addr(&__core2_stack_top), 0),
+            3 => (addr(&__core3_stack_top), 0),
+            _ => (addr(&_stack_top), addr(&__core0_hyp_stack_size)),
+        };
+        StackLayout {
+            top,
+            hyp_size,
+            mode_sizes: [
+                addr(&_und_stack_size),
+                addr(&_svc_stack_size),
+                addr(&_abt_stack_size),
+                addr(&_irq_stack_size),
+                addr(&_fiq_stack_size),
+                addr(&_sys_stack_size),
+            ],
+        }
+    }
+}
+
+/// The RAM that EL0 tasks can use, on core 0
+///
+/// This is the `.task` section, and everything above it up to the bottom of
+/// core 0's stacks.
+pub fn task_ram() -> core::ops::Range<usize> {
+    let task_start = core::ptr::addr_of!(__task_start) as usize;
+    task_start..stack_layout(0).bottom()
+}
+
+/// Build the MPU regions for this core
+///
+/// * Code RAM is read-only, and only `.text` is executable. The flag which
+///   releases the other cores gets its own read-write region.
+/// * This core's Data RAM is read-write and not executable, except for a
+///   no-access guard at the bottom of each mode's stack, so a stack overflow
+///   faults instead of corrupting whatever is below it. On core 0, the task
+///   RAM gets a region of its own, which EL0 can use while a task runs.
+/// * The other cores' Data RAM, and the Shared RAM, are read-write and not
+///   executable. The other cores' Data RAM is folded into the regions for our
+///   statics and our top stack, which it sits next to, to save regions.
+/// * EL0 can only read and execute Code RAM.
+/// * The peripherals and the GIC are Device memory, in one region.
+fn regions(core: usize) -> [El1Region; NUM_REGIONS] {
+    let addr = |sym: &u8| core::ptr::from_ref(sym) as usize;
+    // SAFETY: we only take the addresses of these linker symbols
+    let (release_start, release_end) =
+        unsafe { (addr(&__core_release_start), addr(&__core_release_end)) };
+    let data = &CORE_DATA_RANGES[core];
+    let layout = stack_layout(core);
 
-/// Basic MPU config for the S32Z2
-static MPU_CONFIG: El1Config = El1Config {
-    background_config: false,
-    regions: &[
-        // Code in R52_0_0_CODE_RAM
+    // Each mode's stack, minus its guard, working down from the top. The
+    // first one also covers the HYP stack above it, if there is one, and the
+    // other cores' Data RAM above ours.
+    let mode_stacks = layout.mode_stacks();
+    let mut stacks = [(0, 0); 6];
+    for (stack, mode_stack) in stacks.iter_mut().zip(&mode_stacks) {
+        *stack = (mode_stack.start + STACK_GUARD_SIZE, mode_stack.end);
+    }
+    let bottom = layout.bottom();
+    debug_assert_eq!(layout.top, *data.end() + 1);
+    stacks[0].1 = *DATA_RANGE.end() + 1;
+    // Whatever is below the stacks - `.data` and `.bss`, and on core 0 the
+    // task RAM. The statics also cover the other cores' Data RAM below ours.
+    let (statics, task) = if core == 0 {
+        let task = task_ram();
+        ((*DATA_RANGE.start(), task.start), (task.start, task.end))
+    } else {
+        ((*DATA_RANGE.start(), bottom), (0, 0))
+    };
+
+    let data_rw = |(start, end): (usize, usize)| {
+        if start >= end {
+            return disabled();
+        }
         El1Region {
-            range: 0x3210_0000 as *mut u8..=0x321B_FFFF as *mut u8,
+            range: (start as *mut u8)..=((end - 1) as *mut u8),
             shareability: El1Shareability::InnerShareable,
-            // ordinarily you'd want this read-only, except the debugger
-            // replaces instructions on-the-fly with soft breakpoints, so
-            // it has to be read-write if you want single-step debugging to work.
-            access: El1AccessPerms::ReadWrite,
+            access: El1AccessPerms::ReadWriteNoEL0,
+            no_exec: true,
+            mair: MPU_MAIR_INDEX_DATA,
+            enable: true,
+        }
+    };
+
+    [
+        // Vectors and .text in Code RAM
+        El1Region {
+            range: (*CODE_RANGE.start() as *mut u8)..=((release_start - 1) as *mut u8),
+            shareability: El1Shareability::InnerShareable,
+            access: TEXT_ACCESS,
             no_exec: false,
             mair: MPU_MAIR_INDEX_CODE,
             enable: true,
         },
-        // Data in R52_0_0_CODE_RAM
+        // The core release flag in Code RAM
         El1Region {
-            range: 0x3178_0000 as *mut u8..=0x317B_FFFF as *mut u8,
+            range: (release_start as *mut u8)..=((release_end - 1) as *mut u8),
             shareability: El1Shareability::InnerShareable,
-            access: El1AccessPerms::ReadWrite,
+            access: El1AccessPerms::ReadWriteNoEL0,
             no_exec: true,
-            mair: MPU_MAIR_INDEX_DATA,
+            mair: MPU_MAIR_INDEX_CODE,
             enable: true,
         },
-        // RTU0 P0 Peripherals
+        // .rodata and the .data load image in Code RAM
         El1Region {
-            range: 0x4000_0000 as *mut u8..=0x407F_FFFF as *mut u8,
-            shareability: El1Shareability::NonShareable,
+            range: (release_end as *mut u8)..=(*CODE_RANGE.end() as *mut u8),
+            shareability: El1Shareability::InnerShareable,
+            access: El1AccessPerms::ReadOnly,
+            no_exec: true,
+            mair: MPU_MAIR_INDEX_CODE,
+            enable: true,
+        },
+        // Our statics, the task RAM, then each of our stacks, plus the other
+        // cores' Data RAM either side of them
+        data_rw(statics),
+        data_rw(task),
+        data_rw(stacks[5]),
+        data_rw(stacks[4]),
+        data_rw(stacks[3]),
+        data_rw(stacks[2]),
+        data_rw(stacks[1]),
+        data_rw(stacks[0]),
+        // Shared data in the cluster's Shared RAM
+        El1Region {
+            range: range(SHARED_RANGE),
+            shareability: El1Shareability::OuterShareable,
             access: El1AccessPerms::ReadWriteNoEL0,
             no_exec: true,
-            mair: MPU_MAIR_INDEX_DEVICE,
+            mair: MPU_MAIR_INDEX_SHARED,
             enable: true,
         },
-        // RTU0 GICv3
+        // The peripherals and the GIC
         El1Region {
-            range: 0x4780_0000 as *mut u8..=0x479F_FFFF as *mut u8,
+            range: range(PERIPHERAL_RANGE),
             shareability: El1Shareability::NonShareable,
             access: El1AccessPerms::ReadWriteNoEL0,
             no_exec: true,
             mair: MPU_MAIR_INDEX_DEVICE,
             enable: true,
         },
-    ],
-    memory_attributes: &[
-        // MPU_MAIR_INDEX_CODE
-        MemAttr::NormalMemory {
-            outer: Cacheable::WriteThroughNonTransient(RwAllocPolicy::R),
-            inner: Cacheable::WriteThroughNonTransient(RwAllocPolicy::R),
-        },
-        // MPU_MAIR_INDEX_DATA
-        MemAttr::NormalMemory {
-            outer: Cacheable::WriteBackNonTransient(RwAllocPolicy::R),
-            inner: Cacheable::WriteBackNonTransient(RwAllocPolicy::R),
-        },
-        // MPU_MAIR_INDEX_DEVICE
-        MemAttr::DeviceMemory,
-    ],
-};
+    ]
+}
+
+/// A region which is switched off
+const fn disabled() -> El1Region {
+    El1Region {
+        range: core::ptr::null_mut()..=(0x3F as *mut u8),
+        shareability: El1Shareability::NonShareable,
+        access: El1AccessPerms::ReadOnlyNoEL0,
+        no_exec: true,
+        mair: 0,
+        enable: false,
+    }
+}
+
+/// Convert an address range into the pointer range an `El1Region` wants
+const fn range(range: core::ops::RangeInclusive<usize>) -> core::ops::RangeInclusive<*mut u8> {
+    (*range.start() as *mut u8)..=(*range.end() as *mut u8)
+}
 
 /// Set up the MPU
 ///
 /// This is *mandatory* on S32Z2 because the peripherals are in
 /// 'Normal' memory according to the default MPU memory map, which
 /// absolutely does not work for talking to peripherals.
-pub fn enable() {
+pub(crate) fn enable() -> Result<(), Error> {
     let mut mpu = unsafe { El1Mpu::new() };
     if VERBOSE_DEBUGGING {
         arm_dcc::dprintln!("MPU Config before:");
-        for idx in 0..mpu.num_regions() {
-            if let Some(region) = mpu.get_region(idx) {
-                if region.enable {
-                    arm_dcc::dprintln!("{:02}: {:?}", idx, region);
-                }
-            }
-        }
+        print_regions(&mut mpu);
     }
 
-    mpu.configure(&MPU_CONFIG).expect("MPU Config");
+    // Put the regions we use first, so everything after them is spare
+    let mut regions = regions(crate::core_id());
+    regions.sort_unstable_by_key(|region| !region.enable);
+    let used = regions.iter().filter(|region| region.enable).count();
+    let config = El1Config {
+        background_config: false,
+        regions: &regions[..used],
+        memory_attributes: &MEMORY_ATTRIBUTES,
+    };
+    mpu.configure(&config).map_err(|_| Error::Mpu)?;
+    for idx in used..usize::from(mpu.num_regions()) {
+        mpu.set_region(idx as u8, &disabled())
+            .map_err(|_| Error::Mpu)?;
+    }
+    FIRST_SPARE[crate::core_id()].store(used as u32, Ordering::Relaxed);
+    RESERVED[crate::core_id()].store(0, Ordering::Relaxed);
 
     arm_dcc::dprintln!("MPU Config after:");
+    print_regions(&mut mpu);
+    mpu.enable();
+    Ok(())
+}
+
+/// Print every enabled MPU region
+fn print_regions(mpu: &mut El1Mpu) {
     for idx in 0..mpu.num_regions() {
         if let Some(region) = mpu.get_region(idx) {
             if region.enable {
@@ -112,5 +384,130 @@ pub fn enable() {
             }
         }
     }
-    mpu.enable();
+}
+
+/// Let EL0 use the task RAM, or lock it out again
+///
+/// Only core 0 has task RAM.
+#[cfg(feature = "el0-tasks")]
+pub(crate) fn set_task_access(el0: bool) -> Result<(), Error> {
+    if crate::core_id() != 0 {
+        return Err(Error::NoTaskRam);
+    }
+    let start = task_ram().start;
+    // SAFETY: we only change the access permissions of the task RAM
+    let mut mpu = unsafe { El1Mpu::new() };
+    for idx in 0..mpu.num_regions() {
+        let Some(mut region) = mpu.get_region(idx) else {
+            continue;
+        };
+        if region.enable && *region.range.start() as usize == start {
+            region.access = if el0 {
+                El1AccessPerms::ReadWrite
+            } else {
+                El1AccessPerms::ReadWriteNoEL0
+            };
+            mpu.set_region(idx, &region).map_err(|_| Error::Mpu)?;
+            cortex_ar::asm::dsb();
+            cortex_ar::asm::isb();
+            return Ok(());
+        }
+    }
+    Err(Error::NoTaskRam)
+}
+
+/// A spare MPU region, reserved for use by this core
+///
+/// The region is switched off when this is dropped. It belongs to the core
+/// that reserved it - each core has its own MPU - so it can't be sent to
+/// another core.
+#[derive(Debug)]
+pub struct Region {
+    index: u8,
+    not_send: PhantomData<*const ()>,
+}
+
+/// Reserve a spare MPU region on this core
+///
+/// It starts off disabled.
+pub fn reserve() -> Result<Region, Error> {
+    critical_section::with(|_| {
+        // SAFETY: we only ask how many regions there are
+        let num_regions = u32::from(unsafe { El1Mpu::new() }.num_regions());
+        let core = crate::core_id();
+        let reserved = RESERVED[core].load(Ordering::Relaxed);
+        let index = (FIRST_SPARE[core].load(Ordering::Relaxed)..num_regions)
+            .find(|idx| reserved & (1 << idx) == 0)
+            .ok_or(Error::NoFreeRegions)?;
+        RESERVED[core].store(reserved | (1 << index), Ordering::Relaxed);
+        Ok(Region {
+            index: index as u8,
+            not_send: PhantomData,
+        })
+    })
+}
+
+impl Region {
+    /// The MPU region number
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Program this region
+    ///
+    /// The region must start and end on a 64-byte boundary, and must not
+    /// overlap any other enabled region. If you change the cacheability of
+    /// memory that is in use, clean and invalidate it from the cache first.
+    pub fn set(&mut self, region: El1Region) -> Result<(), Error> {
+        let start = *region.range.start() as usize;
+        let end = *region.range.end() as usize;
+        if start % REGION_ALIGNMENT != 0
+            || end % REGION_ALIGNMENT != REGION_ALIGNMENT - 1
+            || end < start
+        {
+            return Err(Error::Unaligned);
+        }
+        // SAFETY: Regions we haven't reserved are only ever read
+        let mut mpu = unsafe { El1Mpu::new() };
+        critical_section::with(|_| {
+            if region.enable {
+                for idx in (0..mpu.num_regions()).filter(|idx| *idx != self.index) {
+                    let Some(other) = mpu.get_region(idx) else {
+                        continue;
+                    };
+                    let other_start = *other.range.start() as usize;
+                    let other_end = *other.range.end() as usize;
+                    if other.enable && start <= other_end && other_start <= end {
+                        return Err(Error::Overlap { region: idx });
+                    }
+                }
+            }
+            mpu.set_region(self.index, &region)
+                .map_err(|_| Error::Mpu)?;
+            cortex_ar::asm::dsb();
+            cortex_ar::asm::isb();
+            Ok(())
+        })
+    }
+
+    /// Switch this region off, but keep it reserved
+    pub fn disable(&mut self) {
+        // SAFETY: We own this region
+        let mut mpu = unsafe { El1Mpu::new() };
+        // A disabled region can't fail to program
+        let _ = mpu.set_region(self.index, &disabled());
+        cortex_ar::asm::dsb();
+        cortex_ar::asm::isb();
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        self.disable();
+        critical_section::with(|_| {
+            let core = crate::core_id();
+            let reserved = RESERVED[core].load(Ordering::Relaxed);
+            RESERVED[core].store(reserved & !(1 << self.index), Ordering::Relaxed);
+        });
+    }
 }
@@ -0,0 +1,160 @@
+//! Message Receive Unit code for the S32Z2
+//!
+//! Each *MRU* (Message Receive Unit) has a number of channels, and each
+//! channel has up to four 32-bit mailboxes. The owner of an MRU receives
+//! messages in it - to send, you write into the *other* side's MRU. Filling
+//! mailbox 0 of a channel can raise an interrupt for the receiver.
+//!
+//! Messages are sent by writing mailbox 0 last, so the receiver never sees a
+//! half-written message.
+//!
+//! We can only send to the Cortex-M33 system manager for now. Receiving needs
+//! the base addresses and interrupt numbers of the RTU0 cores' own MRUs, and
+//! we have no checked values for those.
+
+/// Base address of the MRU belonging to the Cortex-M33 system manager
+///
+/// Not yet checked against the memory map in the S32Z2 Reference Manual.
+const SMU_MRU_BASE: usize = 0x4520_0000;
+
+/// Offset from the MRU base to the mailboxes for channel 0
+const MAILBOX_OFFSET: usize = 0x1000;
+
+/// Distance between the channel configuration registers
+const CHANNEL_STRIDE: usize = 0x10;
+
+/// Distance between the mailboxes for each channel
+const MAILBOX_STRIDE: usize = 0x1000;
+
+/// How many channels each MRU has
+pub const NUM_CHANNELS: usize = 12;
+
+/// How many mailboxes each channel has
+pub const NUM_MAILBOXES: usize = 4;
+
+/// Errors that can occur when using an MRU
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// There is no such channel
+    NoSuchChannel,
+    /// The receiver has not yet taken the last message on this channel
+    MailboxFull,
+    /// The message is too long for the channel
+    BadMessage,
+}
+
+/// The MRU instances we can send to
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Instance {
+    /// The MRU for the Cortex-M33 system manager
+    Smu,
+}
+
+impl Instance {
+    /// The base address of this MRU instance
+    pub const fn base_address(self) -> usize {
+        match self {
+            Instance::Smu => SMU_MRU_BASE,
+        }
+    }
+}
+
+/// The configuration registers for one MRU channel
+#[derive(derive_mmio::Mmio)]
+#[repr(C)]
+pub struct MruChannel {
+    /// Channel Configuration 0 and 1, offset: 0x0, which only the receiver
+    /// sets up
+    _cfg: [u32; 2],
+    /// Channel Mailbox Status, offset: 0x8
+    mbstat: MruChMbstat,
+}
+
+/// The Channel Mailbox Status Register
+///
+/// Bits are set when a mailbox is written, and cleared by writing one to
+/// them.
+#[bitbybit::bitfield(u32)]
+pub struct MruChMbstat {
+    /// Which mailboxes are full, one bit each
+    #[bits(0..=3, rw)]
+    mbs: arbitrary_int::u4,
+}
+
+impl core::fmt::Debug for MruChMbstat {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "MruChMbstat(mbs={:04b})", self.mbs())
+    }
+}
+
+/// The mailboxes for one MRU channel
+#[derive(derive_mmio::Mmio)]
+#[repr(C)]
+pub struct MruMailboxes {
+    /// Mailbox data, offset: 0x0
+    mb: [u32; NUM_MAILBOXES],
+}
+
+/// Something that can be sent through an MRU channel
+pub trait Message {
+    /// How many 32-bit words this message needs (1 to 4)
+    const WORDS: usize;
+
+    /// Convert the message to words. Only the first `WORDS` are sent.
+    fn encode(&self) -> [u32; NUM_MAILBOXES];
+}
+
+impl Message for u32 {
+    const WORDS: usize = 1;
+
+    fn encode(&self) -> [u32; NUM_MAILBOXES] {
+        [*self, 0, 0, 0]
+    }
+}
+
+impl Message for [u32; NUM_MAILBOXES] {
+    const WORDS: usize = NUM_MAILBOXES;
+
+    fn encode(&self) -> [u32; NUM_MAILBOXES] {
+        *self
+    }
+}
+
+/// Get the configuration registers for a channel
+fn channel_regs(instance: Instance, channel: usize) -> Result<MmioMruChannel<'static>, Error> {
+    if channel >= NUM_CHANNELS {
+        return Err(Error::NoSuchChannel);
+    }
+    let addr = instance.base_address() + (channel * CHANNEL_STRIDE);
+    Ok(unsafe { MruChannel::new_mmio_at(addr) })
+}
+
+/// Get the mailboxes for a channel
+fn mailboxes(instance: Instance, channel: usize) -> Result<MmioMruMailboxes<'static>, Error> {
+    if channel >= NUM_CHANNELS {
+        return Err(Error::NoSuchChannel);
+    }
+    let addr = instance.base_address() + MAILBOX_OFFSET + (channel * MAILBOX_STRIDE);
+    Ok(unsafe { MruMailboxes::new_mmio_at(addr) })
+}
+
+/// Send a message to a channel on someone else's MRU
+///
+/// Fails with [`Error::MailboxFull`] if they have not yet taken the last
+/// message.
+pub fn try_send<M: Message>(instance: Instance, channel: usize, message: &M) -> Result<(), Error> {
+    if M::WORDS == 0 || M::WORDS > NUM_MAILBOXES {
+        return Err(Error::BadMessage);
+    }
+    let regs = channel_regs(instance, channel)?;
+    if regs.read_mbstat().mbs().value() & 1 != 0 {
+        return Err(Error::MailboxFull);
+    }
+    let mut mailboxes = mailboxes(instance, channel)?;
+    let words = message.encode();
+    // Mailbox 0 goes last, as it triggers the receiver
+    for idx in (0..M::WORDS).rev() {
+        let _ = mailboxes.write_mb(idx, words[idx]);
+    }
+    Ok(())
+}
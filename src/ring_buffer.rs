@@ -0,0 +1,229 @@
+//! A lock-free single-producer, single-consumer ring buffer
+//!
+//! This is plain Rust with no hardware dependencies, so the producer and
+//! consumer can be on different cores (see [`crate::ipc`]), or on the host.
+//!
+//! The buffer only uses atomic loads and stores - never read-modify-write
+//! operations - so it works in non-cacheable memory where exclusive accesses
+//! may not be supported. All-zero bytes is a valid empty buffer, so it can
+//! live in a `NOLOAD` section that the start-up code zeroes.
+
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A ring buffer holding up to `N` values of type `T`
+pub struct RingBuffer<T, const N: usize> {
+    /// Count of values ever read. Only written by the consumer.
+    head: AtomicUsize,
+    /// Count of values ever written. Only written by the producer.
+    tail: AtomicUsize,
+    /// Storage for the values
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+}
+
+// SAFETY: The producer and consumer only ever touch different slots, and the
+// head and tail indices are atomic.
+unsafe impl<T: Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    /// Create a new, empty, ring buffer
+    pub const fn new() -> RingBuffer<T, N> {
+        RingBuffer {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+        }
+    }
+
+    /// Get the producer end of the buffer
+    ///
+    /// # Safety
+    ///
+    /// There must only be one producer for this buffer at any time.
+    pub unsafe fn producer(&self) -> Producer<'_, T, N> {
+        Producer { ring: self }
+    }
+
+    /// Get the consumer end of the buffer
+    ///
+    /// # Safety
+    ///
+    /// There must only be one consumer for this buffer at any time.
+    pub unsafe fn consumer(&self) -> Consumer<'_, T, N> {
+        Consumer { ring: self }
+    }
+
+    /// How many values the buffer can hold
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// How many values are currently in the buffer
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        let head = self.head.load(Ordering::Acquire);
+        tail.wrapping_sub(head)
+    }
+
+    /// Is the buffer currently empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The producer end of a [`RingBuffer`]
+pub struct Producer<'a, T, const N: usize> {
+    ring: &'a RingBuffer<T, N>,
+}
+
+impl<T: Copy, const N: usize> Producer<'_, T, N> {
+    /// Add a value to the buffer
+    ///
+    /// Gives the value back if the buffer is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        let head = self.ring.head.load(Ordering::Acquire);
+        if N == 0 || tail.wrapping_sub(head) >= N {
+            return Err(value);
+        }
+        let slot = &self.ring.slots[tail % N];
+        // SAFETY: The consumer does not read this slot until we move the tail
+        // past it, and we are the only producer.
+        unsafe {
+            slot.get().write(MaybeUninit::new(value));
+        }
+        self.ring
+            .tail
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Is the buffer currently full?
+    pub fn is_full(&self) -> bool {
+        self.ring.len() >= N
+    }
+}
+
+/// The consumer end of a [`RingBuffer`]
+pub struct Consumer<'a, T, const N: usize> {
+    ring: &'a RingBuffer<T, N>,
+}
+
+impl<T: Copy, const N: usize> Consumer<'_, T, N> {
+    /// Take the oldest value from the buffer, if any
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        let tail = self.ring.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let slot = &self.ring.slots[head % N];
+        // SAFETY: The producer wrote this slot before moving the tail past it,
+        // and does not write it again until we move the head past it.
+        let value = unsafe { slot.get().read().assume_init() };
+        self.ring
+            .head
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Is the buffer currently empty?
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_empty() {
+        let ring = RingBuffer::<u32, 4>::new();
+        let mut consumer = unsafe { ring.consumer() };
+        assert!(ring.is_empty());
+        assert_eq!(ring.len(), 0);
+        assert_eq!(ring.capacity(), 4);
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn first_in_first_out() {
+        let ring = RingBuffer::<u32, 4>::new();
+        let mut producer = unsafe { ring.producer() };
+        let mut consumer = unsafe { ring.consumer() };
+        producer.push(1).unwrap();
+        producer.push(2).unwrap();
+        producer.push(3).unwrap();
+        assert_eq!(ring.len(), 3);
+        assert_eq!(consumer.pop(), Some(1));
+        assert_eq!(consumer.pop(), Some(2));
+        assert_eq!(consumer.pop(), Some(3));
+        assert_eq!(consumer.pop(), None);
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn full_buffer_gives_value_back() {
+        let ring = RingBuffer::<u32, 2>::new();
+        let mut producer = unsafe { ring.producer() };
+        let mut consumer = unsafe { ring.consumer() };
+        producer.push(1).unwrap();
+        producer.push(2).unwrap();
+        assert!(producer.is_full());
+        assert_eq!(producer.push(3), Err(3));
+        assert_eq!(consumer.pop(), Some(1));
+        assert!(!producer.is_full());
+        producer.push(3).unwrap();
+        assert_eq!(consumer.pop(), Some(2));
+        assert_eq!(consumer.pop(), Some(3));
+    }
+
+    #[test]
+    fn wraps_around() {
+        let ring = RingBuffer::<u32, 3>::new();
+        let mut producer = unsafe { ring.producer() };
+        let mut consumer = unsafe { ring.consumer() };
+        for value in 0..100 {
+            producer.push(value).unwrap();
+            producer.push(value + 1000).unwrap();
+            assert_eq!(consumer.pop(), Some(value));
+            assert_eq!(consumer.pop(), Some(value + 1000));
+        }
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn indices_wrap_at_usize_max() {
+        let ring = RingBuffer::<u32, 4>::new();
+        ring.head.store(usize::MAX - 1, Ordering::Relaxed);
+        ring.tail.store(usize::MAX - 1, Ordering::Relaxed);
+        let mut producer = unsafe { ring.producer() };
+        let mut consumer = unsafe { ring.consumer() };
+        for value in 0..4 {
+            producer.push(value).unwrap();
+        }
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(ring.len(), 4);
+        for value in 0..4 {
+            assert_eq!(consumer.pop(), Some(value));
+        }
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let ring = RingBuffer::<u32, 0>::new();
+        let mut producer = unsafe { ring.producer() };
+        let mut consumer = unsafe { ring.consumer() };
+        assert_eq!(producer.push(1), Err(1));
+        assert_eq!(consumer.pop(), None);
+    }
+}
@@ -0,0 +1,336 @@
+//! Hardware Semaphore code for the S32Z2
+//!
+//! The *SEMA42* has sixteen gates. Each gate is either unlocked, or locked by
+//! one particular bus master, so they give mutual exclusion between the
+//! Cortex-R52 cores, the Cortex-M33 system manager and other masters.
+//!
+//! With the `critical-section-multi-core` feature, gate
+//! [`CRITICAL_SECTION_GATE`] also backs the `critical-section` implementation.
+//! Gate [`HANDLER_GATE`] guards the interrupt and system call handler tables.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::core_id;
+use crate::gic::NUM_CORES;
+
+/// Base address of the SEMA42
+///
+/// Not yet checked against the memory map in the S32Z2 Reference Manual. If
+/// nothing at this address behaves like a SEMA42 gate, [`lock_value`] panics
+/// rather than handing out locks that exclude nobody.
+const SEMA42_BASE: usize = 0x4046_0000;
+
+/// How many gates the SEMA42 has
+pub const NUM_GATES: usize = 16;
+
+/// The gate used to implement `critical_section` across cores
+pub const CRITICAL_SECTION_GATE: usize = NUM_GATES - 1;
+
+/// The gate held whilst registering interrupt and system call handlers
+///
+/// The handler tables are in `.shared`, where we can't rely on exclusive
+/// accesses, so this stops two cores registering the same entry at once.
+/// See [`with_gate`].
+pub const HANDLER_GATE: usize = NUM_GATES - 2;
+
+/// The value in a gate register when the gate is unlocked
+const GATE_UNLOCKED: u8 = 0;
+
+/// The first value written to `RSTGT` to reset a gate
+const RSTGT_KEY: u16 = 0xE2 << 8;
+
+/// The second value written to `RSTGT` to reset a gate
+const RSTGT_KEY_INVERTED: u16 = 0x1D << 8;
+
+/// How many times in a row a free gate can ignore our lock value before
+/// [`lock`] decides the lock value is wrong, rather than that another master
+/// took and released the gate in between our accesses
+const WRONG_LOCK_VALUE_LIMIT: u32 = 100;
+
+/// The lock value each core has found, or [`GATE_UNLOCKED`] if it has not
+/// looked yet
+///
+/// In `.shared`, so each core can check that no other core found the same
+/// value. Each core only writes its own entry.
+#[link_section = ".shared"]
+static LOCK_VALUES: [AtomicU8; NUM_CORES] = [const { AtomicU8::new(GATE_UNLOCKED) }; NUM_CORES];
+
+/// Errors that can occur when locking a gate
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// There is no such gate
+    NoSuchGate,
+    /// The gate is held by another master, or by this core
+    Locked,
+    /// The gate was free, but did not take our [`lock_value`]
+    WrongLockValue,
+}
+
+/// The SEMA42 registers
+#[derive(derive_mmio::Mmio)]
+#[repr(C)]
+pub struct Sema42 {
+    /// Gate registers, offset: 0x0
+    ///
+    /// Zero means unlocked, otherwise it is the bus master number plus one.
+    /// These are indexed by byte offset - use [`gate_offset`] to find a gate.
+    gate: [u8; NUM_GATES],
+    _reserved: [u8; 0x32],
+    /// Reset Gate, offset: 0x42
+    rstgt: u16,
+}
+
+/// Get a handle to the SEMA42
+///
+/// # Safety
+///
+/// The gates are designed to be shared, so holding more than one handle is
+/// fine, but only unlock or reset gates you own.
+pub unsafe fn sema42() -> MmioSema42<'static> {
+    unsafe { Sema42::new_mmio_at(SEMA42_BASE) }
+}
+
+/// The byte offset of a gate register
+///
+/// The gates are big-endian within each 32-bit word, so GATE3 is at offset 0
+/// and GATE0 at offset 3.
+pub const fn gate_offset(gate: usize) -> usize {
+    gate ^ 3
+}
+
+/// The value this core writes to lock a gate
+///
+/// This is our logical bus master number plus one. The SEMA42 ignores writes
+/// of any other non-zero value from us, so rather than trust a table of
+/// master numbers, each core finds its value the first time it asks, by
+/// trying every value on a free gate.
+pub fn lock_value() -> u8 {
+    let core = core_id();
+    match LOCK_VALUES[core].load(Ordering::SeqCst) {
+        GATE_UNLOCKED => {
+            let value = find_lock_value();
+            LOCK_VALUES[core].store(value, Ordering::SeqCst);
+            // If two cores look at the same time, at least one of them sees
+            // the other's value here
+            let shared = LOCK_VALUES
+                .iter()
+                .enumerate()
+                .any(|(other, v)| other != core && v.load(Ordering::SeqCst) == value);
+            assert!(!shared, "SEMA42 cannot tell our cores apart");
+            value
+        }
+        value => value,
+    }
+}
+
+/// Find the value which locks a gate for this core
+///
+/// Picks a free gate and writes each possible value in turn, until one
+/// reads back. That one is ours, so we unlock the gate again and return it.
+/// If another master takes the gate part-way through, we try that value
+/// again on another free gate.
+///
+/// Panics if no value locks the gate, which means there is no SEMA42 at
+/// [`SEMA42_BASE`].
+fn find_lock_value() -> u8 {
+    let mut sema = unsafe { sema42() };
+    let mut value = 1;
+    loop {
+        let free = (0..NUM_GATES)
+            .map(gate_offset)
+            .find(|offset| sema.read_gate(*offset).is_ok_and(|v| v == GATE_UNLOCKED));
+        let Some(offset) = free else {
+            // every gate is in use - wait for one to come free
+            core::hint::spin_loop();
+            continue;
+        };
+        let _ = sema.write_gate(offset, value);
+        match sema.read_gate(offset) {
+            Ok(locked) if locked == value => {
+                let _ = sema.write_gate(offset, GATE_UNLOCKED);
+                return value;
+            }
+            Ok(GATE_UNLOCKED) => {
+                value = value
+                    .checked_add(1)
+                    .expect("No SEMA42 gate took any lock value");
+            }
+            // another master took the gate - try this value again
+            _ => {}
+        }
+    }
+}
+
+/// A gate which this core has locked
+///
+/// The gate is unlocked when this is dropped.
+#[derive(Debug)]
+pub struct GateGuard {
+    gate: usize,
+}
+
+impl GateGuard {
+    /// Which gate this guard holds
+    pub fn gate(&self) -> usize {
+        self.gate
+    }
+}
+
+impl Drop for GateGuard {
+    fn drop(&mut self) {
+        unlock(self.gate);
+    }
+}
+
+/// Try to lock a gate, without waiting
+///
+/// Fails with [`Error::Locked`] if the gate is held by anyone (including
+/// this core).
+pub fn try_lock(gate: usize) -> Result<GateGuard, Error> {
+    if gate >= NUM_GATES {
+        return Err(Error::NoSuchGate);
+    }
+    let offset = gate_offset(gate);
+    let mut sema = unsafe { sema42() };
+    let value = lock_value();
+    let read_gate = |sema: &mut MmioSema42| sema.read_gate(offset).map_err(|_| Error::NoSuchGate);
+    if read_gate(&mut sema)? != GATE_UNLOCKED {
+        return Err(Error::Locked);
+    }
+    let _ = sema.write_gate(offset, value);
+    match read_gate(&mut sema)? {
+        GATE_UNLOCKED => return Err(Error::WrongLockValue),
+        locked if locked != value => return Err(Error::Locked),
+        _ => {}
+    }
+    // make sure nothing we do under the lock happens before we got it
+    cortex_ar::asm::dmb();
+    Ok(GateGuard { gate })
+}
+
+/// Lock a gate, waiting until it is free
+///
+/// Panics if the gate does not exist, or if it keeps ignoring our
+/// [`lock_value`]. Waits forever if this core already holds the gate.
+pub fn lock(gate: usize) -> GateGuard {
+    let mut wrong_values = 0;
+    loop {
+        match try_lock(gate) {
+            Ok(guard) => return guard,
+            Err(Error::NoSuchGate) => panic!("No such SEMA42 gate"),
+            Err(Error::WrongLockValue) => {
+                wrong_values += 1;
+                assert!(
+                    wrong_values < WRONG_LOCK_VALUE_LIMIT,
+                    "SEMA42 gate ignored our lock value"
+                );
+            }
+            Err(Error::Locked) => wrong_values = 0,
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Run `f` with IRQs and FIQs masked and a gate locked
+///
+/// Masking interrupts means nothing else on this core can try to take the
+/// gate whilst we hold it. Panics, like [`lock`], if the gate does not exist
+/// or keeps ignoring our [`lock_value`]. `f` must not take the same gate.
+pub fn with_gate<R>(gate: usize, f: impl FnOnce() -> R) -> R {
+    crate::gic::without_interrupts(|| {
+        let _guard = lock(gate);
+        f()
+    })
+}
+
+/// Does this core currently hold this gate?
+pub fn is_locked_by_us(gate: usize) -> bool {
+    let sema = unsafe { sema42() };
+    sema.read_gate(gate_offset(gate))
+        .is_ok_and(|value| value == lock_value())
+}
+
+/// Unlock a gate which this core holds
+fn unlock(gate: usize) {
+    // make sure everything we did under the lock is done
+    cortex_ar::asm::dmb();
+    let mut sema = unsafe { sema42() };
+    let _ = sema.write_gate(gate_offset(gate), GATE_UNLOCKED);
+}
+
+/// Forcibly unlock a gate, whoever holds it
+///
+/// Useful for recovering after another master was reset whilst holding a
+/// gate.
+///
+/// # Safety
+///
+/// Whoever held the gate will think they still hold it.
+pub unsafe fn reset_gate(gate: usize) {
+    let mut sema = unsafe { sema42() };
+    sema.write_rstgt(RSTGT_KEY);
+    sema.write_rstgt(RSTGT_KEY_INVERTED | gate as u16);
+}
+
+#[cfg(feature = "critical-section-multi-core")]
+mod critical_section_impl {
+    use super::{is_locked_by_us, lock, CRITICAL_SECTION_GATE};
+
+    /// Bit set in the restore state if IRQs were enabled on entry
+    const IRQS_WERE_ENABLED: u8 = 1 << 0;
+
+    /// Bit set in the restore state if this acquire locked the gate
+    const GATE_WAS_LOCKED: u8 = 1 << 1;
+
+    /// Bit set in the restore state if FIQs were enabled on entry
+    const FIQS_WERE_ENABLED: u8 = 1 << 2;
+
+    /// A `critical-section` implementation which works across cores
+    ///
+    /// Masks IRQs and FIQs on this core, then takes a SEMA42 gate to keep the
+    /// other cores out. Masking FIQs too means an FIQ handler can never find
+    /// the gate held by the code it interrupted. Nested critical sections on the same core just see
+    /// that we already hold the gate.
+    struct MultiCoreCriticalSection;
+
+    critical_section::set_impl!(MultiCoreCriticalSection);
+
+    unsafe impl critical_section::Impl for MultiCoreCriticalSection {
+        unsafe fn acquire() -> critical_section::RawRestoreState {
+            let mut state = 0;
+            let cpsr = cortex_ar::register::Cpsr::read();
+            if !cpsr.i() {
+                state |= IRQS_WERE_ENABLED;
+            }
+            if !cpsr.f() {
+                state |= FIQS_WERE_ENABLED;
+            }
+            // Safety: masking interrupts cannot break any invariants
+            unsafe {
+                core::arch::asm!("cpsid if", options(nomem, nostack, preserves_flags));
+            }
+            if !is_locked_by_us(CRITICAL_SECTION_GATE) {
+                // We unlock it in release, not when the guard drops
+                core::mem::forget(lock(CRITICAL_SECTION_GATE));
+                state |= GATE_WAS_LOCKED;
+            }
+            state
+        }
+
+        unsafe fn release(state: critical_section::RawRestoreState) {
+            if (state & GATE_WAS_LOCKED) != 0 {
+                super::unlock(CRITICAL_SECTION_GATE);
+            }
+            if (state & FIQS_WERE_ENABLED) != 0 {
+                unsafe {
+                    core::arch::asm!("cpsie f", options(nomem, nostack, preserves_flags));
+                }
+            }
+            if (state & IRQS_WERE_ENABLED) != 0 {
+                unsafe {
+                    cortex_ar::interrupt::enable();
+                }
+            }
+        }
+    }
+}
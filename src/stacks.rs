@@ -0,0 +1,90 @@
+//! Where the start-up code puts each core's stacks
+//!
+//! This is plain Rust with no hardware dependencies, so it can be tested on
+//! the host. The MPU code uses it to put a guard region at the bottom of each
+//! stack, and to find the task RAM below core 0's stacks, so it must match
+//! what `_stack_setup` (or the secondary core start-up code) actually does.
+//!
+//! Working down from the top of a core's Data RAM, there is first a HYP stack
+//! (on core 0, if `_default_start` ran in EL2 and set one up), then a stack
+//! for each of UND, SVC, ABT, IRQ, FIQ and SYS mode, in that order.
+
+use core::ops::Range;
+
+/// The stacks on one core
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StackLayout {
+    /// The top of the core's Data RAM
+    pub top: usize,
+    /// The size of the HYP stack, or zero if there isn't one
+    pub hyp_size: usize,
+    /// The size of each mode's stack, in the order they are stacked
+    pub mode_sizes: [usize; 6],
+}
+
+impl StackLayout {
+    /// The top of the UND stack, which is the first of the mode stacks
+    pub const fn mode_top(&self) -> usize {
+        self.top - self.hyp_size
+    }
+
+    /// The RAM each mode's stack uses, in the order they are stacked
+    pub fn mode_stacks(&self) -> [Range<usize>; 6] {
+        let mut top = self.mode_top();
+        self.mode_sizes.map(|size| {
+            let stack = (top - size)..top;
+            top -= size;
+            stack
+        })
+    }
+
+    /// The bottom of the SYS stack, which is the lowest of them
+    pub fn bottom(&self) -> usize {
+        self.mode_top() - self.mode_sizes.iter().sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Core 0 in the default build, with the cortex-r-rt stack sizes and our
+    /// SYS stack size
+    const CORE0: StackLayout = StackLayout {
+        top: 0x317C_0000,
+        hyp_size: 0x400,
+        mode_sizes: [0x400, 0x400, 0x400, 0x400, 0x400, 0x8000],
+    };
+
+    #[test]
+    fn mode_stacks_start_below_the_hyp_stack() {
+        let stacks = CORE0.mode_stacks();
+        assert_eq!(stacks[0], 0x317B_F800..0x317B_FC00);
+        assert_eq!(stacks[4], 0x317B_E800..0x317B_EC00);
+        assert_eq!(stacks[5], 0x317B_6800..0x317B_E800);
+    }
+
+    #[test]
+    fn bottom_is_the_bottom_of_the_sys_stack() {
+        assert_eq!(CORE0.bottom(), CORE0.mode_stacks()[5].start);
+        assert_eq!(CORE0.bottom(), 0x317C_0000 - 0x400 - 5 * 0x400 - 0x8000);
+    }
+
+    #[test]
+    fn no_hyp_stack_starts_at_the_top() {
+        let layout = StackLayout {
+            hyp_size: 0,
+            ..CORE0
+        };
+        assert_eq!(layout.mode_stacks()[0].end, layout.top);
+        assert_eq!(layout.bottom(), CORE0.bottom() + 0x400);
+    }
+
+    #[test]
+    fn stacks_are_contiguous() {
+        let stacks = CORE0.mode_stacks();
+        for pair in stacks.windows(2) {
+            assert_eq!(pair[0].start, pair[1].end);
+        }
+    }
+}
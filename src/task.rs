@@ -0,0 +1,360 @@
+//! EL0 tasks, and the system calls they make
+//!
+//! With the `el0-tasks` feature, [`run`] calls a function at EL0, in User
+//! mode. The MPU only lets a task read and execute Code RAM, and read and
+//! write the *task RAM* - the `.task` section on core 0, and everything above
+//! it up to the bottom of the stacks (see [`crate::mpu::task_ram`]). The rest
+//! of RAM and every peripheral is `ReadWriteNoEL0`, so a task which touches
+//! them takes an abort, which goes to the application's abort handler.
+//!
+//! Tasks ask the kernel to do things with system calls. A system call is an
+//! `svc` instruction whose immediate is the call number, with up to four
+//! arguments in r0 to r3, and the result comes back in r0. Use [`syscall!`]
+//! to make one, and [`register`] to provide the kernel function for a call
+//! number. Call [`SYS_EXIT`] ends the task.
+//!
+//! System call handlers run in SVC mode, with IRQs masked. SVCs with an
+//! immediate of [`NUM_SYSCALLS`] or more still go to the
+//! `#[exception(SupervisorCall)]` handler, as they do without this feature.
+//!
+//! A task can be interrupted. System mode shares its stack pointer with User
+//! mode, so the usual IRQ entry code, which saves the interrupted state on the
+//! System mode stack, would put kernel state on the task's stack. With this
+//! feature, an IRQ which interrupts a task is handled in IRQ mode on the IRQ
+//! stack instead, so size `_irq_stack_size` for your IRQ handlers. FIQs are
+//! always handled on the FIQ stack.
+//!
+//! This feature can't be used with `nested-irq`, as nested interrupt handlers
+//! have to run in System mode, so that IRQ mode is free for the next IRQ.
+//!
+//! [`syscall!`]: crate::syscall!
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+
+/// How many system call numbers there are
+pub const NUM_SYSCALLS: usize = 64;
+
+/// The system call which ends the task, with the exit code in r0
+pub const SYS_EXIT: u32 = 0;
+
+/// The result of a system call with no handler
+pub const UNKNOWN_SYSCALL: u32 = u32::MAX;
+
+/// CPSR mode bits for User mode
+const MODE_USR: u32 = 0x10;
+
+/// CPSR mode bits for System mode
+const MODE_SYS: u32 = 0x1F;
+
+/// Errors that can occur when using tasks and system calls
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// There is no such system call number
+    NoSuchSyscall,
+    /// That system call number is used by the kernel itself
+    Reserved,
+    /// That system call already has a handler
+    AlreadyRegistered,
+    /// Tasks can only be started from System mode, and not from another task
+    WrongMode,
+    /// The MPU could not give the task its RAM
+    Mpu(crate::mpu::Error),
+}
+
+/// A kernel function which handles a system call
+///
+/// It gets the caller's r0 to r3, and what it returns goes back in r0.
+pub type Handler = fn([u32; 4]) -> u32;
+
+/// A function to run as a task
+///
+/// It gets the argument passed to [`run`], and what it returns is the exit
+/// code, as if it had called [`exit`].
+pub type Entry = extern "C" fn(u32) -> u32;
+
+/// The system call handlers, as `Handler` function pointers (or 0)
+///
+/// Every core uses the same table. Like the GIC handler tables, it is only
+/// written under [`crate::sema42::HANDLER_GATE`], with plain stores.
+#[link_section = ".shared"]
+static SYSCALLS: [AtomicUsize; NUM_SYSCALLS] = [const { AtomicUsize::new(0) }; NUM_SYSCALLS];
+
+/// Whether a task is running
+static TASK_RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// How to get back to [`run`] when the task exits
+///
+/// The System mode SP, the CPSR, and the SVC mode SP at the time the task
+/// started.
+static KERNEL_CONTEXT: [AtomicU32; 3] = [const { AtomicU32::new(0) }; 3];
+
+extern "C" {
+    fn _s32z2_task_enter(entry: Entry, arg: u32, stack_top: usize, kernel: *mut u32) -> u32;
+    fn _s32z2_task_exit(code: u32, kernel: *const u32) -> !;
+    fn _svc_handler(arg: u32);
+}
+
+/// Make a system call
+///
+/// Takes the call number (which must be a constant) and up to four `u32`
+/// arguments, and evaluates to the `u32` result.
+///
+/// ```rust,ignore
+/// let sum = s32z2_rust_demo::syscall!(SYS_ADD, 2, 3);
+/// ```
+#[macro_export]
+macro_rules! syscall {
+    ($number:expr) => {
+        $crate::syscall!($number, 0, 0, 0, 0)
+    };
+    ($number:expr, $a:expr) => {
+        $crate::syscall!($number, $a, 0, 0, 0)
+    };
+    ($number:expr, $a:expr, $b:expr) => {
+        $crate::syscall!($number, $a, $b, 0, 0)
+    };
+    ($number:expr, $a:expr, $b:expr, $c:expr) => {
+        $crate::syscall!($number, $a, $b, $c, 0)
+    };
+    ($number:expr, $a:expr, $b:expr, $c:expr, $d:expr) => {{
+        let args: [u32; 4] = [$a as u32, $b as u32, $c as u32, $d as u32];
+        let result: u32;
+        // SAFETY: the SVC handler preserves every register apart from r0, and
+        // LR if we are in SVC mode already
+        unsafe {
+            core::arch::asm!(
+                "svc #{number}",
+                number = const $number,
+                inout("r0") args[0] => result,
+                in("r1") args[1],
+                in("r2") args[2],
+                in("r3") args[3],
+                out("lr") _,
+            );
+        }
+        result
+    }};
+}
+
+/// Register the kernel function for a system call
+///
+/// Every core uses the same table.
+pub fn register(number: u32, handler: Handler) -> Result<(), Error> {
+    let slot = syscall_slot(number)?;
+    crate::sema42::with_gate(crate::sema42::HANDLER_GATE, || {
+        if slot.load(Ordering::Acquire) != 0 {
+            return Err(Error::AlreadyRegistered);
+        }
+        slot.store(handler as usize, Ordering::Release);
+        Ok(())
+    })
+}
+
+/// Remove the kernel function for a system call
+pub fn unregister(number: u32) -> Result<(), Error> {
+    let slot = syscall_slot(number)?;
+    crate::sema42::with_gate(crate::sema42::HANDLER_GATE, || {
+        slot.store(0, Ordering::Release);
+    });
+    Ok(())
+}
+
+/// Find the table entry for a system call
+fn syscall_slot(number: u32) -> Result<&'static AtomicUsize, Error> {
+    if number == SYS_EXIT {
+        return Err(Error::Reserved);
+    }
+    SYSCALLS.get(number as usize).ok_or(Error::NoSuchSyscall)
+}
+
+/// Run a function as an EL0 task, and wait for it to exit
+///
+/// The task starts in User mode, with its stack at the top of the task RAM
+/// and IRQs masked or not as they are now. Interrupts taken while it runs are
+/// handled on their own mode's stack, never the task's. Returns the task's
+/// exit code.
+///
+/// Only core 0 has task RAM. Call this from System mode - the normal mode for
+/// application code - and not from an interrupt or system call handler.
+pub fn run(entry: Entry, arg: u32) -> Result<u32, Error> {
+    if current_mode() != MODE_SYS || TASK_RUNNING.load(Ordering::Relaxed) {
+        return Err(Error::WrongMode);
+    }
+    crate::mpu::set_task_access(true).map_err(Error::Mpu)?;
+    TASK_RUNNING.store(true, Ordering::Relaxed);
+    let stack_top = crate::mpu::task_ram().end;
+    // SAFETY: only EL0 code runs on the task stack, and the task can only get
+    // back to us through `SYS_EXIT`, which restores our registers
+    let code =
+        unsafe { _s32z2_task_enter(entry, arg, stack_top, KERNEL_CONTEXT.as_ptr() as *mut u32) };
+    TASK_RUNNING.store(false, Ordering::Relaxed);
+    crate::mpu::set_task_access(false).map_err(Error::Mpu)?;
+    Ok(code)
+}
+
+/// Read the mode bits of the CPSR
+fn current_mode() -> u32 {
+    let cpsr: u32;
+    // SAFETY: reading the CPSR has no side-effects
+    unsafe {
+        core::arch::asm!(
+            "mrs {cpsr}, cpsr",
+            cpsr = out(reg) cpsr,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    cpsr & 0x1F
+}
+
+/// End this task, with an exit code
+///
+/// Call this from a task, at EL0. Anywhere else, the kernel refuses the
+/// system call, and this panics.
+pub fn exit(code: u32) -> ! {
+    let result = crate::syscall!(SYS_EXIT, code);
+    panic!("exit called outside a task (got {result:#x})");
+}
+
+/// Handle an SVC
+///
+/// Called by `_asm_svc_handler`, with the SVC immediate, the caller's r0 to r3
+/// (which we write the result back to), and the caller's CPSR.
+#[no_mangle]
+extern "C" fn _s32z2_svc_handler(number: u32, args: &mut [u32; 4], spsr: u32) {
+    if number as usize >= NUM_SYSCALLS {
+        // SAFETY: this is what cortex-r-rt would have called
+        unsafe { _svc_handler(number) };
+        return;
+    }
+    if number == SYS_EXIT {
+        if spsr & 0x1F == MODE_USR && TASK_RUNNING.load(Ordering::Relaxed) {
+            // SAFETY: a task is running, so `run` saved the kernel context
+            unsafe { _s32z2_task_exit(args[0], KERNEL_CONTEXT.as_ptr() as *const u32) };
+        }
+        args[0] = UNKNOWN_SYSCALL;
+        return;
+    }
+    args[0] = match SYSCALLS[number as usize].load(Ordering::Acquire) {
+        0 => UNKNOWN_SYSCALL,
+        raw => {
+            // SAFETY: we only ever store `Handler` function pointers in the
+            // table
+            let handler: Handler = unsafe { core::mem::transmute::<usize, Handler>(raw) };
+            handler(*args)
+        }
+    };
+}
+
+// SVC entry code, and the code to start and stop EL0 tasks.
+//
+// Replaces the `_asm_default_svc_handler` from cortex-r-rt. We push LR_svc
+// and SPSR_svc first, so a handler can make SVC calls of its own, then the
+// caller's r0 to r3 (for the arguments and result), and the rest of the
+// caller-saved registers, as the handler is an AAPCS function.
+#[cfg(target_arch = "arm")]
+core::arch::global_asm!(
+    r#"
+    .section .text._asm_svc_handler
+    .global _asm_svc_handler
+    .type _asm_svc_handler, %function
+    .arm
+_asm_svc_handler:
+    // Push LR_svc and SPSR_svc to the SVC mode stack
+    srsfd   sp!, #0x13
+    // Save the caller-saved registers, with r12 pointing at r0 to r3
+    push    {{ r0-r3, r12, lr }}
+    mov     r12, sp
+    vpush   {{ s0-s15 }}
+    vmrs    r1, fpscr
+    // Read the immediate from the SVC instruction, in Arm or Thumb state
+    mrs     r2, spsr
+    tst     r2, #0x20
+    ldrhne  r0, [lr, #-2]
+    andne   r0, r0, #0xFF
+    ldreq   r0, [lr, #-4]
+    biceq   r0, r0, #0xFF000000
+    // Align the stack to 8 bytes, remembering how much we moved it
+    and     r3, sp, #4
+    sub     sp, sp, r3
+    push    {{ r1, r3 }}
+    mov     r1, r12
+    bl      _s32z2_svc_handler
+    pop     {{ r1, r3 }}
+    add     sp, sp, r3
+    vmsr    fpscr, r1
+    vpop    {{ s0-s15 }}
+    pop     {{ r0-r3, r12, lr }}
+    // Return to the caller, restoring its CPSR
+    rfefd   sp!
+    .size _asm_svc_handler, . - _asm_svc_handler
+
+    .section .text._s32z2_task_enter
+    .global _s32z2_task_enter
+    .type _s32z2_task_enter, %function
+    .arm
+_s32z2_task_enter:
+    // Save our callee-saved registers, keeping the stack 8-byte aligned
+    push    {{ r4-r12, lr }}
+    vpush   {{ d8-d15 }}
+    vmrs    r4, fpscr
+    push    {{ r4, r5 }}
+    // Remember our SP and CPSR, for when the task exits
+    mrs     r4, cpsr
+    str     sp, [r3, #0]
+    str     r4, [r3, #4]
+    // Drop to User mode from SVC mode, as System mode has no SPSR
+    cps     #0x13
+    str     sp, [r3, #8]
+    and     r4, r4, #0xC0         /* Keep our IRQ and FIQ masks */
+    orr     r4, r4, #0x10         /* User mode, Arm state */
+    msr     spsr_cxsf, r4
+    msr     sp_usr, r2
+    ldr     r4, =_s32z2_task_return
+    msr     lr_usr, r4
+    mov     lr, r0
+    mov     r0, r1
+    // Don't leak our registers to the task
+    mov     r1, #0
+    mov     r2, #0
+    mov     r3, #0
+    mov     r4, #0
+    mov     r5, #0
+    mov     r6, #0
+    mov     r7, #0
+    mov     r8, #0
+    mov     r9, #0
+    mov     r10, #0
+    mov     r11, #0
+    mov     r12, #0
+    movs    pc, lr
+    .size _s32z2_task_enter, . - _s32z2_task_enter
+
+    .section .text._s32z2_task_return
+    .global _s32z2_task_return
+    .type _s32z2_task_return, %function
+    .arm
+_s32z2_task_return:
+    // A task returned, so exit with its return value (already in r0)
+    svc     #{sys_exit}
+    b       _s32z2_task_return
+    .size _s32z2_task_return, . - _s32z2_task_return
+
+    .section .text._s32z2_task_exit
+    .global _s32z2_task_exit
+    .type _s32z2_task_exit, %function
+    .arm
+_s32z2_task_exit:
+    // Throw away the SVC handler's stack, and go back to the caller of
+    // `_s32z2_task_enter`, in System mode, with its IRQ and FIQ masks
+    ldr     sp, [r1, #8]
+    ldr     r2, [r1, #4]
+    msr     cpsr_c, r2
+    ldr     sp, [r1, #0]
+    pop     {{ r4, r5 }}
+    vmsr    fpscr, r4
+    vpop    {{ d8-d15 }}
+    pop     {{ r4-r12, pc }}
+    .size _s32z2_task_exit, . - _s32z2_task_exit
+"#,
+    sys_exit = const SYS_EXIT,
+);
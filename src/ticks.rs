@@ -0,0 +1,74 @@
+//! Conversions between counters running at different rates
+//!
+//! This is plain Rust with no hardware dependencies, so it can be tested on
+//! the host. The maths is done in 128 bits, so no 64-bit tick count can
+//! overflow part-way through. Results too big for 64 bits saturate at
+//! `u64::MAX`, which every user of these functions treats as 'never'.
+
+/// Convert `ticks` at `from_hz` into ticks at `to_hz`, rounding down
+///
+/// Use this for 'what time is it', so that the result is never in the future.
+pub const fn convert_floor(ticks: u64, from_hz: u64, to_hz: u64) -> u64 {
+    saturate(ticks as u128 * to_hz as u128 / from_hz as u128)
+}
+
+/// Convert `ticks` at `from_hz` into ticks at `to_hz`, rounding up
+///
+/// Use this for deadlines, so that they never expire early.
+pub const fn convert_ceil(ticks: u64, from_hz: u64, to_hz: u64) -> u64 {
+    saturate((ticks as u128 * to_hz as u128).div_ceil(from_hz as u128))
+}
+
+/// Clamp a 128-bit tick count into 64 bits
+const fn saturate(ticks: u128) -> u64 {
+    if ticks > u64::MAX as u128 {
+        u64::MAX
+    } else {
+        ticks as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_rate_is_unchanged() {
+        assert_eq!(convert_floor(12_345, 1_000_000, 1_000_000), 12_345);
+        assert_eq!(convert_ceil(12_345, 1_000_000, 1_000_000), 12_345);
+    }
+
+    #[test]
+    fn floor_rounds_down_and_ceil_rounds_up() {
+        // 1 tick at 3 Hz is a third of a tick at 1 Hz
+        assert_eq!(convert_floor(1, 3, 1), 0);
+        assert_eq!(convert_ceil(1, 3, 1), 1);
+        assert_eq!(convert_floor(3, 3, 1), 1);
+        assert_eq!(convert_ceil(3, 3, 1), 1);
+    }
+
+    #[test]
+    fn embassy_ticks_to_a_slower_counter() {
+        // 1 MHz Embassy ticks on a counter running at 8 MHz, and back again
+        assert_eq!(convert_ceil(1_000, 1_000_000, 8_000_000), 8_000);
+        assert_eq!(convert_floor(8_000, 8_000_000, 1_000_000), 1_000);
+        // 7 counter ticks is not yet a whole Embassy tick
+        assert_eq!(convert_floor(7, 8_000_000, 1_000_000), 0);
+    }
+
+    #[test]
+    fn round_trip_never_expires_early() {
+        let (counter_hz, embassy_hz) = (24_000_000, 1_000_000);
+        for deadline in [0, 1, 999, 1_000_001, 1 << 40] {
+            let counter = convert_ceil(deadline, embassy_hz, counter_hz);
+            assert!(convert_floor(counter, counter_hz, embassy_hz) >= deadline);
+        }
+    }
+
+    #[test]
+    fn saturates_instead_of_overflowing() {
+        assert_eq!(convert_ceil(u64::MAX, 1, 2), u64::MAX);
+        assert_eq!(convert_floor(u64::MAX, 1, 2), u64::MAX);
+        assert_eq!(convert_floor(u64::MAX, 2, 1), u64::MAX / 2);
+    }
+}
@@ -0,0 +1,306 @@
+//! Monotonic time and software alarms for the S32Z2
+//!
+//! Time comes from the EL1 Virtual Timer, which counts up at the rate given
+//! in `CNTFRQ`. [`Instant`] and [`Duration`] hold a 64-bit tick count, so they
+//! will not wrap in the lifetime of the system.
+//!
+//! Each core has a small queue of alarms. The timer compare value is always
+//! set to the earliest deadline in the queue, and the timer PPI calls each
+//! alarm's callback once it has expired. Call [`init`] on each core that wants
+//! alarms.
+
+use core::cell::UnsafeCell;
+use core::num::NonZeroUsize;
+
+use arm_gic::IntId;
+use cortex_ar::generic_timer::{El1VirtualTimer, GenericTimer};
+use critical_section::Mutex;
+
+pub use crate::alarm_queue::AlarmId;
+use crate::alarm_queue::AlarmQueue;
+use crate::gic::{self, Gic, InterruptConfig, NUM_CORES};
+
+/// The PPI for the virtual timer, according to the Cortex-R52 Reference Manual
+///
+/// This corresponds to Interrupt ID 27.
+pub const VIRTUAL_TIMER_PPI: IntId = crate::interrupt::Interrupt::VirtualTimer.int_id();
+
+/// How many alarms each core can have waiting
+pub const NUM_ALARMS: usize = 8;
+
+/// Errors that can occur when using alarms
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Every alarm slot on this core is in use
+    NoFreeAlarms,
+    /// Could not set up the timer interrupt
+    Gic(gic::Error),
+}
+
+impl From<gic::Error> for Error {
+    fn from(value: gic::Error) -> Self {
+        Error::Gic(value)
+    }
+}
+
+/// The frequency of the system counter, in Hz
+pub fn frequency_hz() -> u32 {
+    cortex_ar::register::Cntfrq::read().0
+}
+
+/// A point in time, as measured by the system counter
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    ticks: u64,
+}
+
+impl Instant {
+    /// The time now
+    pub fn now() -> Instant {
+        // Safety: we only read the counter
+        let timer = unsafe { El1VirtualTimer::new() };
+        Instant {
+            ticks: timer.counter(),
+        }
+    }
+
+    /// Make an instant from a raw counter value
+    pub const fn from_ticks(ticks: u64) -> Instant {
+        Instant { ticks }
+    }
+
+    /// The raw counter value
+    pub const fn ticks(self) -> u64 {
+        self.ticks
+    }
+
+    /// How long since the counter started
+    pub const fn duration_since_epoch(self) -> Duration {
+        Duration { ticks: self.ticks }
+    }
+
+    /// How long since this instant, or zero if it is in the future
+    pub fn elapsed(self) -> Duration {
+        Instant::now().saturating_duration_since(self)
+    }
+
+    /// How long from `earlier` to this instant, if `earlier` really is earlier
+    pub const fn checked_duration_since(self, earlier: Instant) -> Option<Duration> {
+        match self.ticks.checked_sub(earlier.ticks) {
+            Some(ticks) => Some(Duration { ticks }),
+            None => None,
+        }
+    }
+
+    /// How long from `earlier` to this instant, or zero if it isn't earlier
+    pub const fn saturating_duration_since(self, earlier: Instant) -> Duration {
+        Duration {
+            ticks: self.ticks.saturating_sub(earlier.ticks),
+        }
+    }
+
+    /// Move this instant forwards, unless that would overflow
+    pub const fn checked_add(self, duration: Duration) -> Option<Instant> {
+        match self.ticks.checked_add(duration.ticks) {
+            Some(ticks) => Some(Instant { ticks }),
+            None => None,
+        }
+    }
+}
+
+impl core::ops::Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant {
+            ticks: self.ticks + rhs.ticks,
+        }
+    }
+}
+
+impl core::ops::Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        Duration {
+            ticks: self.ticks - rhs.ticks,
+        }
+    }
+}
+
+/// A span of time, as measured by the system counter
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Duration {
+    ticks: u64,
+}
+
+impl Duration {
+    /// No time at all
+    pub const ZERO: Duration = Duration { ticks: 0 };
+
+    /// Make a duration from a number of counter ticks
+    pub const fn from_ticks(ticks: u64) -> Duration {
+        Duration { ticks }
+    }
+
+    /// Make a duration from a number of seconds
+    pub fn from_secs(secs: u64) -> Duration {
+        Duration {
+            ticks: secs * u64::from(frequency_hz()),
+        }
+    }
+
+    /// Make a duration from a number of milliseconds
+    pub fn from_millis(millis: u64) -> Duration {
+        Duration {
+            ticks: millis * u64::from(frequency_hz()) / 1_000,
+        }
+    }
+
+    /// Make a duration from a number of microseconds
+    pub fn from_micros(micros: u64) -> Duration {
+        Duration {
+            ticks: micros * u64::from(frequency_hz()) / 1_000_000,
+        }
+    }
+
+    /// The number of counter ticks
+    pub const fn ticks(self) -> u64 {
+        self.ticks
+    }
+
+    /// The number of whole seconds
+    pub fn as_secs(self) -> u64 {
+        self.ticks / u64::from(frequency_hz())
+    }
+
+    /// The number of whole milliseconds
+    pub fn as_millis(self) -> u64 {
+        self.ticks * 1_000 / u64::from(frequency_hz())
+    }
+
+    /// The number of whole microseconds
+    pub fn as_micros(self) -> u64 {
+        self.ticks * 1_000_000 / u64::from(frequency_hz())
+    }
+}
+
+impl core::ops::Add<Duration> for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        Duration {
+            ticks: self.ticks + rhs.ticks,
+        }
+    }
+}
+
+impl From<Duration> for core::time::Duration {
+    fn from(value: Duration) -> core::time::Duration {
+        core::time::Duration::from_micros(value.as_micros())
+    }
+}
+
+/// A function called when an alarm expires
+///
+/// It runs in interrupt context, and can set another alarm.
+pub type Callback = fn(AlarmId);
+
+/// The alarms for each core
+///
+/// One image runs on every core, so these live in `.shared` where every core
+/// can see them. Anywhere else would be in core 0's cacheable data RAM, and
+/// the cores' L1 data caches are not coherent with each other. An all-zero
+/// [`AlarmQueue`] is empty, so the zeroed `.shared` section is a valid
+/// starting state.
+#[link_section = ".shared"]
+static ALARMS: [Mutex<UnsafeCell<AlarmQueue<NUM_ALARMS>>>; NUM_CORES] =
+    [const { Mutex::new(UnsafeCell::new(AlarmQueue::new())) }; NUM_CORES];
+
+/// Run `f` on this core's alarm queue, in a critical section
+fn with_alarms<R>(f: impl FnOnce(&mut AlarmQueue<NUM_ALARMS>) -> R) -> R {
+    critical_section::with(|cs| {
+        // Safety: only this core touches its own queue, only in a critical
+        // section, and `f` never calls back into this module
+        let alarms = unsafe { &mut *ALARMS[crate::core_id()].borrow(cs).get() };
+        f(alarms)
+    })
+}
+
+/// Set up the Virtual Timer and its interrupt on this core
+///
+/// The timer is enabled, with no deadline.
+pub fn init(gic: &mut Gic, priority: u8) -> Result<(), Error> {
+    // Safety: we are the only user of the virtual timer compare value
+    let mut timer = unsafe { El1VirtualTimer::new() };
+    timer.counter_compare_set(u64::MAX);
+    timer.interrupt_mask(false);
+    timer.enable(true);
+    gic::register_handler(VIRTUAL_TIMER_PPI, handle_timer_irq)?;
+    gic.configure(VIRTUAL_TIMER_PPI, &InterruptConfig::new(priority))?;
+    Ok(())
+}
+
+/// Call `callback` once `deadline` has passed
+///
+/// The callback runs on this core.
+pub fn set_alarm(deadline: Instant, callback: Callback) -> Result<AlarmId, Error> {
+    let callback = NonZeroUsize::new(callback as usize).expect("function pointers are never null");
+    with_alarms(|alarms| {
+        let id = alarms
+            .insert(deadline.ticks, callback)
+            .ok_or(Error::NoFreeAlarms)?;
+        program_timer(alarms);
+        Ok(id)
+    })
+}
+
+/// Call `callback` once `duration` has passed
+pub fn set_alarm_after(duration: Duration, callback: Callback) -> Result<AlarmId, Error> {
+    set_alarm(Instant::now() + duration, callback)
+}
+
+/// Cancel an alarm set on this core
+///
+/// Returns `false` if the alarm has already fired, or was already cancelled.
+pub fn cancel_alarm(id: AlarmId) -> bool {
+    with_alarms(|alarms| {
+        let was_set = alarms.cancel(id);
+        program_timer(alarms);
+        was_set
+    })
+}
+
+/// Set the timer compare value to the earliest deadline, if any
+fn program_timer(alarms: &AlarmQueue<NUM_ALARMS>) {
+    let earliest = alarms.earliest().unwrap_or(u64::MAX);
+    // Safety: only the alarm queue moves the compare value
+    let mut timer = unsafe { El1VirtualTimer::new() };
+    timer.counter_compare_set(earliest);
+}
+
+/// Run when the virtual timer PPI fires
+///
+/// Takes each expired alarm out of the queue, and calls its callback outside
+/// of the critical section so that it can set a new alarm.
+fn handle_timer_irq(_int_id: IntId) {
+    loop {
+        let expired = with_alarms(|alarms| {
+            let expired = alarms.pop_expired(Instant::now().ticks);
+            if expired.is_none() {
+                program_timer(alarms);
+            }
+            expired
+        });
+        match expired {
+            Some((id, callback)) => {
+                // Safety: we only ever put `Callback` function pointers in
+                // the queue
+                let callback: Callback =
+                    unsafe { core::mem::transmute::<usize, Callback>(callback.get()) };
+                callback(id)
+            }
+            None => break,
+        }
+    }
+}